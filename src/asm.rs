@@ -0,0 +1,354 @@
+use crate::chip8::{MAX_ROM_SIZE, PROGRAM_START};
+use crate::symbols::Symbols;
+use std::fmt;
+
+const ORIGIN: u16 = PROGRAM_START as u16;
+
+/// An instruction operand. Numbers and labels are kept as text until all
+/// label addresses are known.
+enum Operand {
+    V(u16),
+    I,
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    B,
+    Value(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::V(x) => write!(f, "V{:X}", x),
+            Operand::I => write!(f, "I"),
+            Operand::IndirectI => write!(f, "[I]"),
+            Operand::DT => write!(f, "DT"),
+            Operand::ST => write!(f, "ST"),
+            Operand::K => write!(f, "K"),
+            Operand::F => write!(f, "F"),
+            Operand::B => write!(f, "B"),
+            Operand::Value(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+impl Operand {
+    fn parse(operand: &str) -> Operand {
+        let upper = operand.to_uppercase();
+        match upper.as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::DT,
+            "ST" => Operand::ST,
+            "K" => Operand::K,
+            "F" => Operand::F,
+            "B" => Operand::B,
+            _ if upper.len() == 2 && upper.starts_with('V') => {
+                match u16::from_str_radix(&upper[1..], 16) {
+                    Ok(x) => Operand::V(x),
+                    Err(_) => Operand::Value(operand.to_string()),
+                }
+            }
+            _ => Operand::Value(operand.to_string()),
+        }
+    }
+}
+
+struct Statement {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+/// Assembles CHIP-8 source in the syntax produced by `disasm` into a ROM
+/// image loaded at 0x200.
+///
+/// Each line holds an optional `label:`, an optional instruction and an
+/// optional `; comment`. The address and opcode columns of `disasm` output
+/// are skipped, so a disassembly assembles back into the same ROM. Besides the instructions, `DB` and `DW` emit raw
+/// bytes and words. Numbers are decimal, `0x`/`$`/`#` hexadecimal or `0b`
+/// binary, and labels can be used wherever an address or number is expected.
+/// Returns the ROM and the addresses of the labels.
+pub fn assemble(source: &str) -> Result<(Vec<u8>, Symbols), String> {
+    let mut labels = Symbols::default();
+    let mut statements = Vec::new();
+    let mut address = ORIGIN;
+
+    // First pass: collect label addresses
+    for (i, line) in source.lines().enumerate() {
+        let mut line = skip_listing(line.split(';').next().unwrap_or("").trim());
+        if let Some(colon) = line.find(':') {
+            let label = line[..colon].trim();
+            if label.is_empty() || label.contains(char::is_whitespace) {
+                return Err(format!("line {}: invalid label: {}", i + 1, label));
+            }
+            labels
+                .insert(label, address)
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            line = line[colon + 1..].trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match line.find(char::is_whitespace) {
+            Some(space) => (&line[..space], line[space..].trim()),
+            None => (line, ""),
+        };
+        let operands: Vec<Operand> = if operands.is_empty() {
+            Vec::new()
+        } else {
+            operands
+                .split(',')
+                .map(|o| Operand::parse(o.trim()))
+                .collect()
+        };
+        let mnemonic = mnemonic.to_uppercase();
+        let size = match mnemonic.as_str() {
+            "DB" => operands.len(),
+            "DW" => operands.len() * 2,
+            _ => 2,
+        };
+        // Stop at the end of memory, before the address can overflow
+        if address as usize + size > PROGRAM_START + MAX_ROM_SIZE {
+            return Err(format!(
+                "line {}: program too large, over {} bytes",
+                i + 1,
+                MAX_ROM_SIZE
+            ));
+        }
+        address += size as u16;
+        statements.push(Statement {
+            line: i + 1,
+            mnemonic,
+            operands,
+        });
+    }
+
+    // Second pass: encode
+    let mut rom = Vec::new();
+    for statement in statements.iter() {
+        let error = |e: String| format!("line {}: {}", statement.line, e);
+        match statement.mnemonic.as_str() {
+            "DB" => {
+                for operand in statement.operands.iter() {
+                    rom.push(value(operand, &labels, 0xFF).map_err(error)? as u8);
+                }
+            }
+            "DW" => {
+                for operand in statement.operands.iter() {
+                    let word = value(operand, &labels, 0xFFFF).map_err(error)?;
+                    rom.push((word >> 8) as u8);
+                    rom.push(word as u8);
+                }
+            }
+            _ => {
+                let opcode = encode(statement, &labels).map_err(error)?;
+                rom.push((opcode >> 8) as u8);
+                rom.push(opcode as u8);
+            }
+        }
+    }
+
+    Ok((rom, labels))
+}
+
+fn encode(statement: &Statement, labels: &Symbols) -> Result<u16, String> {
+    use Operand::*;
+
+    let nnn = |o: &Operand| value(o, labels, 0xFFF);
+    let nn = |o: &Operand| value(o, labels, 0xFF);
+    let n = |o: &Operand| value(o, labels, 0xF);
+
+    let opcode = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SYS", [a]) => nnn(a)?,
+        ("JP", [V(0), a]) => 0xB000 | nnn(a)?,
+        ("JP", [a]) => 0x1000 | nnn(a)?,
+        ("CALL", [a]) => 0x2000 | nnn(a)?,
+        ("SE", [V(x), V(y)]) => 0x5000 | x << 8 | y << 4,
+        ("SE", [V(x), b]) => 0x3000 | x << 8 | nn(b)?,
+        ("SNE", [V(x), V(y)]) => 0x9000 | x << 8 | y << 4,
+        ("SNE", [V(x), b]) => 0x4000 | x << 8 | nn(b)?,
+        ("LD", [V(x), V(y)]) => 0x8000 | x << 8 | y << 4,
+        ("LD", [V(x), DT]) => 0xF007 | x << 8,
+        ("LD", [V(x), K]) => 0xF00A | x << 8,
+        ("LD", [V(x), IndirectI]) => 0xF065 | x << 8,
+        ("LD", [V(x), b]) => 0x6000 | x << 8 | nn(b)?,
+        ("LD", [I, a]) => 0xA000 | nnn(a)?,
+        ("LD", [DT, V(x)]) => 0xF015 | x << 8,
+        ("LD", [ST, V(x)]) => 0xF018 | x << 8,
+        ("LD", [F, V(x)]) => 0xF029 | x << 8,
+        ("LD", [B, V(x)]) => 0xF033 | x << 8,
+        ("LD", [IndirectI, V(x)]) => 0xF055 | x << 8,
+        ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+        ("ADD", [V(x), V(y)]) => 0x8004 | x << 8 | y << 4,
+        ("ADD", [V(x), b]) => 0x7000 | x << 8 | nn(b)?,
+        ("OR", [V(x), V(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [V(x), V(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [V(x), V(y)]) => 0x8003 | x << 8 | y << 4,
+        ("SUB", [V(x), V(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [V(x)]) => 0x8006 | x << 8 | x << 4,
+        ("SHR", [V(x), V(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SUBN", [V(x), V(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [V(x)]) => 0x800E | x << 8 | x << 4,
+        ("SHL", [V(x), V(y)]) => 0x800E | x << 8 | y << 4,
+        ("RND", [V(x), b]) => 0xC000 | x << 8 | nn(b)?,
+        ("DRW", [V(x), V(y), h]) => 0xD000 | x << 8 | y << 4 | n(h)?,
+        ("SKP", [V(x)]) => 0xE09E | x << 8,
+        ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+        (mnemonic, operands) => {
+            let operands: Vec<String> = operands.iter().map(|o| o.to_string()).collect();
+            let instruction = format!("{} {}", mnemonic, operands.join(", "));
+            return Err(format!("invalid instruction: {}", instruction.trim_end()));
+        }
+    };
+    Ok(opcode)
+}
+
+/// Resolves a number or label operand, checking that it fits in `max`.
+fn value(operand: &Operand, labels: &Symbols, max: u16) -> Result<u16, String> {
+    let text = match operand {
+        Operand::Value(text) => text,
+        _ => return Err(format!("expected a number or label, found {}", operand)),
+    };
+    let value = match labels.address(text) {
+        Some(address) => address as u32,
+        None => parse_number(text).ok_or_else(|| format!("unknown label: {}", text))?,
+    };
+    if value > max as u32 {
+        return Err(format!("value out of range: {}", text));
+    }
+    Ok(value as u16)
+}
+
+/// Strips the "200: 6A02" address and opcode in front of an instruction in
+/// `disasm` output, or "3FF: 12" in front of the `DB` for an odd last byte.
+fn skip_listing(line: &str) -> &str {
+    let is_hex = |word: &str| !word.is_empty() && word.chars().all(|c| c.is_ascii_hexdigit());
+    let rest = match line.split_once(':') {
+        Some((address, rest)) if address.len() <= 4 && is_hex(address) => rest.trim(),
+        _ => return line,
+    };
+    let (opcode, instruction) = match rest.split_once(char::is_whitespace) {
+        Some((opcode, instruction)) if is_hex(opcode) => (opcode, instruction.trim()),
+        _ => return line,
+    };
+    match opcode.len() {
+        4 => instruction,
+        2 if instruction.to_uppercase().starts_with("DB ") => instruction,
+        _ => line,
+    }
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower
+        .strip_prefix("0x")
+        .or_else(|| lower.strip_prefix('$'))
+        .or_else(|| lower.strip_prefix('#'))
+    {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble_rom;
+    use std::fs;
+    use std::path::Path;
+
+    fn rom(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().0
+    }
+
+    fn error(source: &str) -> String {
+        assemble(source).err().unwrap()
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "CLS\nld v3, vA\nLD I, 0x123\nDRW V0, V1, 5\nLD [I], VF\nJP V0, 0x300";
+        assert_eq!(
+            rom(source),
+            [0x00, 0xE0, 0x83, 0xA0, 0xA1, 0x23, 0xD0, 0x15, 0xFF, 0x55, 0xB3, 0x00]
+        );
+    }
+
+    #[test]
+    fn number_forms() {
+        assert_eq!(
+            rom("LD V0, 42\nLD V1, 0x2A\nLD V2, $2A\nLD V3, #2A\nLD V4, 0b101010"),
+            [0x60, 42, 0x61, 42, 0x62, 42, 0x63, 42, 0x64, 42]
+        );
+        assert_eq!(rom("DB 1, 0xFF\nDW 0x1234"), [0x01, 0xFF, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn listing_columns() {
+        assert_eq!(
+            rom("200: 6A02  LD VA, 0x02\n202: 12    DB 0x12"),
+            [0x6A, 0x02, 0x12]
+        );
+        // Labels that look like addresses still work
+        assert_eq!(
+            rom("ADD: ADD V0, 1\nF00: DB 1\nJP F00"),
+            [0x70, 0x01, 0x01, 0x12, 0x02]
+        );
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+        for entry in fs::read_dir(roms).unwrap() {
+            let path = entry.unwrap().path();
+            let bytes = fs::read(&path).unwrap();
+            let source = disassemble_rom(&bytes, ORIGIN, &Symbols::default()).join("\n");
+            assert_eq!(rom(&source), bytes, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let (rom, symbols) =
+            assemble("start: CALL sub ; forward\nJP start\nsub:\n  RET\ndata: DW data").unwrap();
+        assert_eq!(rom, [0x22, 0x04, 0x12, 0x00, 0x00, 0xEE, 0x02, 0x06]);
+        assert_eq!(symbols.address("start"), Some(0x200));
+        assert_eq!(symbols.address("sub"), Some(0x204));
+        assert_eq!(symbols.address("data"), Some(0x206));
+    }
+
+    #[test]
+    fn rejected() {
+        assert_eq!(error("a: CLS\na: RET"), "line 2: duplicate label: a");
+        assert_eq!(error("CLS\nJP nowhere"), "line 2: unknown label: nowhere");
+        assert_eq!(error("LD V0, 256"), "line 1: value out of range: 256");
+        assert_eq!(error("my label: CLS"), "line 1: invalid label: my label");
+        assert_eq!(error("NOP"), "line 1: invalid instruction: NOP");
+        assert_eq!(
+            error("add [i], va"),
+            "line 1: invalid instruction: ADD [I], VA"
+        );
+        assert_eq!(
+            error("LD I, V0"),
+            "line 1: expected a number or label, found V0"
+        );
+        assert_eq!(error("DB data\ndata:"), "line 1: value out of range: data");
+        let too_big = "CLS\n".repeat(MAX_ROM_SIZE / 2 + 1);
+        assert_eq!(
+            error(&too_big),
+            "line 1793: program too large, over 3584 bytes"
+        );
+        let data = format!("DB {}", vec!["0"; 70000].join(","));
+        assert_eq!(error(&data), "line 1: program too large, over 3584 bytes");
+        assert_eq!(rom(&"CLS\n".repeat(MAX_ROM_SIZE / 2)).len(), MAX_ROM_SIZE);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::config::AudioConfig;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::Sdl;
+
+/// Sample rate asked for when opening the audio device
+const SAMPLE_RATE: i32 = 44100;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Result<Waveform, String> {
+        match name {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!(
+                "Unknown waveform: {} (expected square, sine, triangle or noise)",
+                name
+            )),
+        }
+    }
+}
+
+/// Opens the default audio device and starts a silent `Beeper` on it.
+pub fn open(
+    sdl_context: &Sdl,
+    config: &AudioConfig,
+    waveform: Waveform,
+) -> Result<AudioDevice<Beeper>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+    let desired_spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE),
+        channels: Some(1), // mono
+        samples: None,     // default sample size
+    };
+
+    let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
+        // Show obtained AudioSpec
+        println!("{:?}", spec);
+
+        // The device may not run at the rate asked for
+        let nyquist = spec.freq as f32 / 2.0;
+        if config.frequency > nyquist {
+            eprintln!(
+                "Warning: the audio device plays at most {} Hz, the beeper is lowered from {} Hz",
+                nyquist, config.frequency
+            );
+        }
+        Beeper::new(config, waveform, spec.freq)
+    })?;
+    device.resume();
+    Ok(device)
+}
+
+/// The CHIP-8 beeper. The audio device keeps running all the time; instead of
+/// pausing it, which pops, the tone is faded in and out by a short linear
+/// attack/release envelope.
+///
+/// The sound timer is counted down here in samples rather than by the frame
+/// loop, so a tone lasts exactly as many 60ths of a second as the timer was
+/// set to, however late the frames run.
+pub struct Beeper {
+    pub muted: bool,
+    /// Samples left until the sound timer runs out
+    remaining: u64,
+    /// Samples per 60 Hz timer tick
+    samples_per_tick: f64,
+    /// Stay silent for timer values below 2, like the COSMAC VIP
+    vip_timing: bool,
+    waveform: Waveform,
+    phase_inc: f32,
+    phase: f32,
+    volume: f32,
+    /// Current envelope level from 0.0 to 1.0
+    level: f32,
+    attack_step: f32,
+    release_step: f32,
+    /// xorshift state for the noise waveform
+    noise: u32,
+    noise_sample: f32,
+}
+
+impl Beeper {
+    pub fn new(config: &AudioConfig, waveform: Waveform, sample_rate: i32) -> Self {
+        let sample_rate = sample_rate as f32;
+        // Per sample level change for a ramp over `ms` milliseconds
+        let step = |ms: f32| 1.0 / (ms / 1000.0 * sample_rate).max(1.0);
+        Beeper {
+            muted: config.mute,
+            remaining: 0,
+            samples_per_tick: sample_rate as f64 / 60.0,
+            vip_timing: config.vip_timing,
+            waveform,
+            // Tones above half the sample rate can't be played
+            phase_inc: config.frequency.min(sample_rate / 2.0) / sample_rate,
+            phase: 0.0,
+            volume: config.volume,
+            level: 0.0,
+            attack_step: step(config.attack_ms),
+            release_step: step(config.release_ms),
+            noise: 0x12345678,
+            noise_sample: 0.0,
+        }
+    }
+
+    /// Starts a tone for `sound_timer` ticks, replacing the one playing.
+    /// 0 stops the tone.
+    pub fn start(&mut self, sound_timer: u8) {
+        self.remaining = if self.vip_timing && sound_timer < 2 {
+            0
+        } else {
+            (sound_timer as f64 * self.samples_per_tick).round() as u64
+        };
+    }
+
+    fn sample(&mut self) -> f32 {
+        match self.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (self.phase * 2.0 * std::f32::consts::PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise_sample,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.phase += self.phase_inc;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+            // Noise holds each random value for one period, so the frequency
+            // still affects its pitch
+            self.noise ^= self.noise << 13;
+            self.noise ^= self.noise >> 17;
+            self.noise ^= self.noise << 5;
+            self.noise_sample = self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        }
+    }
+}
+
+impl AudioCallback for Beeper {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            if self.remaining > 0 && !self.muted {
+                self.level = (self.level + self.attack_step).min(1.0);
+            } else {
+                self.level = (self.level - self.release_step).max(0.0);
+            }
+            *x = self.volume * self.level * self.sample();
+            self.advance();
+            self.remaining = self.remaining.saturating_sub(1);
+        }
+    }
+}
@@ -1,9 +1,26 @@
 extern crate rand;
 
-use rand::Rng;
+use crate::profile::Profile;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
 use std::fs;
 
-const FONTSET: [u8; 80] = [
+/// Display width in pixels
+pub const WIDTH: usize = 64;
+/// Display height in pixels
+pub const HEIGHT: usize = 32;
+/// Address programs are loaded at
+pub const PROGRAM_START: usize = 0x200;
+/// Largest program that fits between the start address and the end of memory
+pub const MAX_ROM_SIZE: usize = 4096 - PROGRAM_START;
+/// Return addresses the stack holds by default
+pub const STACK_DEPTH: usize = 16;
+/// Return addresses the COSMAC VIP interpreter had room for
+pub const VIP_STACK_DEPTH: usize = 12;
+
+/// The hex digit sprites FX29 points I at, 5 rows of 4 pixels each
+pub const FONTSET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, //0
     0x20, 0x60, 0x20, 0x20, 0x70, //1
     0xF0, 0x10, 0xF0, 0x80, 0xF0, //2
@@ -22,47 +39,229 @@ const FONTSET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, //F
 ];
 
+/// Behaviours that differ between CHIP-8 interpreters. The defaults match what
+/// this emulator has always done; the presets follow the original COSMAC VIP
+/// interpreter and SUPER-CHIP.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    /// FX55 and FX65 leave I pointing past the last register stored or loaded
+    pub load_store_inc_i: bool,
+    /// BNNN jumps to NNN plus VX (where X is the highest nibble of NNN) instead of V0
+    pub jump_vx: bool,
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub logic_reset_vf: bool,
+    /// DXYN waits for the next 60 Hz vertical blank, so at most one sprite is
+    /// drawn per frame. Games drawn this way flicker a lot less.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub const PRESETS: [&'static str; 3] = ["default", "vip", "schip"];
+
+    pub fn from_preset(name: &str) -> Result<Quirks, String> {
+        match name {
+            "default" => Ok(Quirks::default()),
+            "vip" => Ok(Quirks {
+                shift_vy: true,
+                load_store_inc_i: true,
+                jump_vx: false,
+                logic_reset_vf: true,
+                display_wait: true,
+            }),
+            "schip" => Ok(Quirks {
+                shift_vy: false,
+                load_store_inc_i: false,
+                jump_vx: true,
+                logic_reset_vf: false,
+                display_wait: false,
+            }),
+            _ => Err(format!(
+                "Unknown quirks preset: {} (expected one of {})",
+                name,
+                Quirks::PRESETS.join(", ")
+            )),
+        }
+    }
+}
+
+/// What happens when an instruction accesses memory past 4 KiB.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutOfBounds {
+    /// The address wraps around to the start of memory, as the address lines
+    /// of the hardware would
+    Wrap,
+    /// Execution stops with `Fault::MemoryOutOfBounds`
+    Fault,
+}
+
+impl OutOfBounds {
+    pub fn from_name(name: &str) -> Result<OutOfBounds, String> {
+        match name {
+            "wrap" => Ok(OutOfBounds::Wrap),
+            "fault" => Ok(OutOfBounds::Fault),
+            _ => Err(format!(
+                "Unknown out of bounds behaviour: {} (expected wrap or fault)",
+                name
+            )),
+        }
+    }
+}
+
+/// An error that stops execution.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    /// The opcode at `pc` isn't a CHIP-8 instruction
+    UnknownOpcode { pc: u16, opcode: u16 },
+    /// The call at `pc` found all `depth` levels of the stack in use
+    StackOverflow { pc: u16, depth: usize },
+    /// The return at `pc` found the stack empty
+    StackUnderflow { pc: u16 },
+    /// The instruction `opcode` at `pc` accessed memory past 4 KiB
+    MemoryOutOfBounds {
+        pc: u16,
+        opcode: u16,
+        address: usize,
+    },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode {:04X} at {:03X}", opcode, pc)
+            }
+            Fault::StackOverflow { pc, depth } => write!(
+                f,
+                "Stack overflow at {:03X}: call with all {} levels of the stack in use",
+                pc, depth
+            ),
+            Fault::StackUnderflow { pc } => {
+                write!(
+                    f,
+                    "Stack underflow at {:03X}: return with an empty stack",
+                    pc
+                )
+            }
+            Fault::MemoryOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "Memory access out of bounds at {:03X}: {:04X} accessed {:X}",
+                pc, opcode, address
+            ),
+        }
+    }
+}
+
+/// A byte of memory written by an instruction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MemoryWrite {
+    pub address: u16,
+    /// The value before the write
+    pub old: u8,
+    pub value: u8,
+}
+
+/// What happened during a call to `Chip8::run_frame`.
+pub struct Frame {
+    /// Whether the display changed
+    pub draw: bool,
+    /// The value the ROM set the sound timer to, if it did
+    pub sound: Option<u8>,
+    /// Instructions executed
+    pub cycles: u32,
+}
+
 pub struct Chip8 {
     pub key: [u8; 16],
-    pub gfx: [u8; 64 * 32],
+    pub gfx: [u8; WIDTH * HEIGHT],
     pub draw_flag: bool,
     pub sound_timer: u8,
-    pc: u16,
-    opcode: u16,
-    i: u16,
-    sp: u16,
-    v: [u8; 16],
-    stack: [u16; 16],
-    memory: [u8; 4096],
-    delay_timer: u8,
+    pub quirks: Quirks,
+    /// What happens on memory accesses past 4 KiB
+    pub out_of_bounds: OutOfBounds,
+    pub pc: u16,
+    /// The instruction executed last
+    pub opcode: u16,
+    pub i: u16,
+    pub sp: u16,
+    pub v: [u8; 16],
+    stack: Vec<u16>,
+    pub memory: [u8; 4096],
+    pub delay_timer: u8,
+    /// Memory written by the last instruction
+    pub last_writes: Vec<MemoryWrite>,
+    /// Addresses of the data the last instruction read from memory
+    pub last_reads: Vec<u16>,
+    /// Execution counts, when profiling
+    pub profile: Option<Profile>,
+    rng: StdRng,
+    /// Set by `tick_timers`, cleared when a sprite is drawn
+    vblank: bool,
+    /// 60 Hz frames run so far, counted by `tick_timers`
+    pub frame: u64,
 }
 
 impl Chip8 {
     pub fn new() -> Self {
         let mut chip = Chip8 {
             key: [0; 16],
-            gfx: [0; 2048],
+            gfx: [0; WIDTH * HEIGHT],
             draw_flag: false,
             pc: 0x200,
             opcode: 0,
             i: 0,
             sp: 0,
             v: [0; 16],
-            stack: [0; 16],
+            stack: vec![0; STACK_DEPTH],
             memory: [0; 4096],
             delay_timer: 0,
+            last_writes: Vec::new(),
+            last_reads: Vec::new(),
+            profile: None,
             sound_timer: 0,
+            quirks: Quirks::default(),
+            out_of_bounds: OutOfBounds::Wrap,
+            rng: StdRng::from_entropy(),
+            vblank: false,
+            frame: 0,
         };
         for (i, font) in FONTSET.iter().enumerate() {
             chip.memory[i] = *font;
         }
         chip
     }
-    pub fn emulate_cycle(&mut self) {
+
+    /// Seeds the random number generator used by CXNN, making runs reproducible.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Executes the instruction at `pc`. Returns false without executing it
+    /// when it is a DXYN waiting for the vertical blank, see
+    /// `Quirks::display_wait`.
+    pub fn emulate_cycle(&mut self) -> Result<bool, Fault> {
         self.draw_flag = false;
+        self.last_writes.clear();
+        self.last_reads.clear();
 
-        self.opcode =
-            (self.memory[self.pc as usize] as u16) << 8 | self.memory[self.pc as usize + 1] as u16;
+        let pc = self.pc;
+        self.opcode = self.fetch()?;
+        if self.opcode & 0xF000 == 0xD000 && self.quirks.display_wait {
+            // Stall on this instruction until the next frame starts
+            if !self.vblank {
+                return Ok(false);
+            }
+            self.vblank = false;
+        }
+        if let Some(profile) = &mut self.profile {
+            let stack = &self.stack[..(self.sp as usize).min(self.stack.len())];
+            profile.record(self.pc, self.opcode, stack, &self.memory);
+        }
         match self.opcode & 0xF000 {
             0x0000 => {
                 match self.opcode & 0x000F {
@@ -76,11 +275,14 @@ impl Chip8 {
                     }
                     // 0x00EE: Returns from subroutine
                     0x000E => {
+                        if self.sp == 0 {
+                            return Err(Fault::StackUnderflow { pc: self.pc });
+                        }
                         self.sp -= 1;
                         self.pc = self.stack[self.sp as usize];
                         self.pc += 2;
                     }
-                    _ => println!("Unknown opcode [0x0000]: {:X?}", self.opcode),
+                    _ => return Err(self.unknown_opcode()),
                 }
             }
             // 0x1NNN: Jumps to address NNN
@@ -89,6 +291,12 @@ impl Chip8 {
             }
             // 0x2NNN: Calls subroutine at NNN
             0x2000 => {
+                if self.sp as usize >= self.stack.len() {
+                    return Err(Fault::StackOverflow {
+                        pc: self.pc,
+                        depth: self.stack.len(),
+                    });
+                }
                 self.stack[self.sp as usize] = self.pc;
                 self.sp += 1;
                 self.pc = self.opcode & 0x0FFF;
@@ -144,18 +352,27 @@ impl Chip8 {
                     0x0001 => {
                         self.v[((self.opcode & 0x0F00) >> 8) as usize] |=
                             self.v[((self.opcode & 0x00F0) >> 4) as usize];
+                        if self.quirks.logic_reset_vf {
+                            self.v[0xF] = 0;
+                        }
                         self.pc += 2;
                     }
                     // 0x8XY2: Sets VX to "VX AND VY"
                     0x0002 => {
                         self.v[((self.opcode & 0x0F00) >> 8) as usize] &=
                             self.v[((self.opcode & 0x00F0) >> 4) as usize];
+                        if self.quirks.logic_reset_vf {
+                            self.v[0xF] = 0;
+                        }
                         self.pc += 2;
                     }
                     // 0x8XY3: Sets VX to "VX XOR VY"
                     0x0003 => {
                         self.v[((self.opcode & 0x0F00) >> 8) as usize] ^=
                             self.v[((self.opcode & 0x00F0) >> 4) as usize];
+                        if self.quirks.logic_reset_vf {
+                            self.v[0xF] = 0;
+                        }
                         self.pc += 2;
                     }
                     // 0x8XY4: Adds VY to VX. VF is set to 1 when there's a carry, and to 0 when there isn't
@@ -190,6 +407,10 @@ impl Chip8 {
                     }
                     // 0x8XY6: Shifts VX right by one. VF is set to the value of the least significant bit of VX before the shift
                     0x0006 => {
+                        if self.quirks.shift_vy {
+                            self.v[((self.opcode & 0x0F00) >> 8) as usize] =
+                                self.v[((self.opcode & 0x00F0) >> 4) as usize];
+                        }
                         self.v[0xF] = self.v[((self.opcode & 0x0F00) >> 8) as usize] & 0x1;
                         self.v[((self.opcode & 0x0F00) >> 8) as usize] >>= 1;
                         self.pc += 2;
@@ -211,13 +432,15 @@ impl Chip8 {
                     }
                     // 0x8XYE: Shifts VX left by one. VF is set to the value of the most significant bit of VX before the shift
                     0x000E => {
+                        if self.quirks.shift_vy {
+                            self.v[((self.opcode & 0x0F00) >> 8) as usize] =
+                                self.v[((self.opcode & 0x00F0) >> 4) as usize];
+                        }
                         self.v[0xF] = self.v[((self.opcode & 0x0F00) >> 8) as usize] >> 7;
                         self.v[((self.opcode & 0x0F00) >> 8) as usize] <<= 1;
                         self.pc += 2;
                     }
-                    _ => {
-                        println!("Unknown opcode [0x8000]: {:X?}", self.opcode);
-                    }
+                    _ => return Err(self.unknown_opcode()),
                 }
             }
             // 0x9XY0: Skips the next instruction if VX doesn't equal VY
@@ -237,11 +460,16 @@ impl Chip8 {
             }
             // BNNN: Jumps to the address NNN plus V0
             0xB000 => {
-                self.pc = (self.opcode & 0x0FFF) + self.v[0] as u16;
+                let offset = if self.quirks.jump_vx {
+                    self.v[((self.opcode & 0x0F00) >> 8) as usize]
+                } else {
+                    self.v[0]
+                };
+                self.pc = (self.opcode & 0x0FFF) + offset as u16;
             }
             // CXNN: Sets VX to a random number and NN
             0xC000 => {
-                let rnd: u8 = rand::thread_rng().gen();
+                let rnd: u8 = self.rng.gen();
                 self.v[((self.opcode & 0x0F00) >> 8) as usize] =
                     (rnd % 0xFF) & (self.opcode & 0x00FF) as u8;
                 self.pc += 2;
@@ -252,19 +480,23 @@ impl Chip8 {
             // VF is set to 1 if any screen pixels are flipped from set to unset when the sprite is drawn,
             // and to 0 if that doesn't happen
             0xD000 => {
-                let x = (self.opcode & 0x0F00) >> 8 as usize;
-                let y = (self.opcode & 0x00F0) >> 4 as usize;
-                let height = self.opcode & 0x000F;
-                let mut pixel: u16;
+                let x = ((self.opcode & 0x0F00) >> 8) as usize;
+                let y = ((self.opcode & 0x00F0) >> 4) as usize;
+                let height = (self.opcode & 0x000F) as usize;
+
+                // Read the whole sprite first, so a fault leaves the display alone
+                let mut sprite = [0; 15];
+                for (yline, row) in sprite.iter_mut().enumerate().take(height) {
+                    *row = self.read_mem(self.i as usize + yline)?;
+                }
 
                 self.v[0xF] = 0;
-                for yline in 0..height {
-                    let y = (self.v[y as usize] + yline as u8) % 32;
-                    pixel = self.memory[(self.i + yline) as usize] as u16;
+                for (yline, &pixel) in sprite.iter().enumerate().take(height) {
+                    let y = (self.v[y] as usize + yline) % HEIGHT;
                     for xline in 0..8 {
-                        let x = (self.v[x as usize] + xline as u8) % 64;
+                        let x = (self.v[x] as usize + xline) % WIDTH;
                         if (pixel & (0x80 >> xline)) != 0 {
-                            let i = (x as u16 + (y as u16 * 64)) as usize;
+                            let i = x + y * WIDTH;
                             self.v[0xF] |= 1 & self.gfx[i];
                             self.gfx[i] ^= 1;
                         }
@@ -291,7 +523,7 @@ impl Chip8 {
                         self.pc += 2;
                     }
                 }
-                _ => println!("Unknown opcode [0xE000]: {:X?}", self.opcode),
+                _ => return Err(self.unknown_opcode()),
             },
             0xF000 => {
                 match self.opcode & 0x00FF {
@@ -312,7 +544,7 @@ impl Chip8 {
                         }
 
                         if !key_press {
-                            return;
+                            return Ok(true);
                         }
 
                         self.pc += 2;
@@ -329,12 +561,15 @@ impl Chip8 {
                     }
                     // FX1E: Adds VX to I
                     0x001E => {
-                        if self.i + self.v[((self.opcode & 0x0F00) >> 8) as usize] as u16 > 0xFFF {
+                        let i = self
+                            .i
+                            .wrapping_add(self.v[((self.opcode & 0x0F00) >> 8) as usize] as u16);
+                        if i > 0xFFF || i < self.i {
                             self.v[0xF] = 1;
                         } else {
                             self.v[0xF] = 0;
                         }
-                        self.i += self.v[((self.opcode & 0x0F00) >> 8) as usize] as u16;
+                        self.i = i;
                         self.pc += 2;
                     }
                     // FX29: Sets I to the location of the sprite for the character in VX. Characters 0-F (in hexadecimal) are represented by a 4x5 font
@@ -344,33 +579,123 @@ impl Chip8 {
                     }
                     // FX33: Stores the Binary-coded decimal representation of VX at the addresses I, I plus 1, and I plus 2
                     0x0033 => {
-                        self.memory[self.i as usize] =
-                            self.v[((self.opcode & 0x0F00) >> 8) as usize] / 100;
-                        self.memory[(self.i + 1) as usize] =
-                            (self.v[((self.opcode & 0x0F00) >> 8) as usize] / 10) % 10;
-                        self.memory[(self.i + 2) as usize] =
-                            (self.v[((self.opcode & 0x0F00) >> 8) as usize] % 100) % 10;
+                        let vx = self.v[((self.opcode & 0x0F00) >> 8) as usize];
+                        let i = self.i as usize;
+                        // Check the whole range first, so a fault leaves memory alone
+                        self.check_address(i + 2)?;
+                        self.write_mem(i, vx / 100)?;
+                        self.write_mem(i + 1, (vx / 10) % 10)?;
+                        self.write_mem(i + 2, (vx % 100) % 10)?;
                         self.pc += 2;
                     }
                     // FX55: Stores V0 to VX in memory starting at address I
                     0x0055 => {
+                        self.check_address(
+                            self.i as usize + ((self.opcode & 0x0F00) >> 8) as usize,
+                        )?;
                         for i in 0..=((self.opcode & 0x0F00) >> 8) {
-                            self.memory[(self.i + i) as usize] = self.v[i as usize];
+                            self.write_mem(self.i as usize + i as usize, self.v[i as usize])?;
+                        }
+                        if self.quirks.load_store_inc_i {
+                            self.i = self.i.wrapping_add(((self.opcode & 0x0F00) >> 8) + 1);
                         }
                         self.pc += 2;
                     }
                     // FX65: Fills V0 to VX with values from memory starting at address I
                     0x0065 => {
+                        self.check_address(
+                            self.i as usize + ((self.opcode & 0x0F00) >> 8) as usize,
+                        )?;
                         for i in 0..=((self.opcode & 0x0F00) >> 8) {
-                            self.v[i as usize] = self.memory[(self.i + i) as usize];
+                            self.v[i as usize] = self.read_mem(self.i as usize + i as usize)?;
+                        }
+                        if self.quirks.load_store_inc_i {
+                            self.i = self.i.wrapping_add(((self.opcode & 0x0F00) >> 8) + 1);
                         }
                         self.pc += 2;
                     }
-                    _ => println!("Unknown opcode [0xF000]: {:X?}", self.opcode),
+                    _ => return Err(self.unknown_opcode()),
                 }
             }
-            _ => println!("Unknown opcode: {:X?}", self.opcode),
+            _ => return Err(self.unknown_opcode()),
+        }
+
+        // Jumps and skips can take the program counter past the end of memory
+        if self.pc as usize >= self.memory.len() {
+            let address = self.pc as usize;
+            self.pc = pc;
+            self.pc = self.check_address(address)?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the opcode at `pc`.
+    fn fetch(&self) -> Result<u16, Fault> {
+        let high = self.memory[self.check_address(self.pc as usize)? as usize];
+        let low = self.memory[self.check_address(self.pc as usize + 1)? as usize];
+        Ok((high as u16) << 8 | low as u16)
+    }
+
+    /// Reads data from memory, recording the access in `last_reads`.
+    fn read_mem(&mut self, address: usize) -> Result<u8, Fault> {
+        let address = self.check_address(address)?;
+        self.last_reads.push(address);
+        Ok(self.memory[address as usize])
+    }
+
+    /// Writes `value` to memory, recording it in `last_writes`.
+    fn write_mem(&mut self, address: usize, value: u8) -> Result<(), Fault> {
+        let address = self.check_address(address)?;
+        let old = self.memory[address as usize];
+        self.memory[address as usize] = value;
+        self.last_writes.push(MemoryWrite {
+            address,
+            old,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Returns the address in memory `address` refers to, following
+    /// `out_of_bounds` when it is past the end.
+    fn check_address(&self, address: usize) -> Result<u16, Fault> {
+        if address < self.memory.len() {
+            return Ok(address as u16);
+        }
+        match self.out_of_bounds {
+            OutOfBounds::Wrap => Ok((address % self.memory.len()) as u16),
+            OutOfBounds::Fault => Err(Fault::MemoryOutOfBounds {
+                pc: self.pc,
+                // While fetching, this is still the instruction that jumped here
+                opcode: self.opcode,
+                address,
+            }),
+        }
+    }
+
+    fn unknown_opcode(&self) -> Fault {
+        Fault::UnknownOpcode {
+            pc: self.pc,
+            opcode: self.opcode,
         }
+    }
+
+    /// Returns the return addresses on the stack, the innermost last.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..(self.sp as usize).min(self.stack.len())]
+    }
+
+    /// Sets how many return addresses the stack can hold, at least 1.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack.resize(depth.max(1), 0);
+    }
+
+    /// Decrements the delay and sound timers and signals the vertical blank.
+    /// Must be called at 60 Hz, independent of how many instructions are
+    /// executed per second.
+    pub fn tick_timers(&mut self) {
+        self.vblank = true;
+        self.frame += 1;
 
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
@@ -381,6 +706,45 @@ impl Chip8 {
         }
     }
 
+    /// Runs one 60 Hz frame: `cycles` instructions followed by a timer tick.
+    /// A fault stops the frame where it happened.
+    pub fn run_frame(&mut self, cycles: u32) -> Result<Frame, Fault> {
+        self.run_frame_with(cycles, |_, _| {})
+    }
+
+    /// Like `run_frame`, calling `after` with the address of every
+    /// instruction once it has been executed. A DXYN waiting for the vertical
+    /// blank uses up cycles without being executed.
+    pub fn run_frame_with<F: FnMut(&Chip8, u16)>(
+        &mut self,
+        cycles: u32,
+        mut after: F,
+    ) -> Result<Frame, Fault> {
+        let sound_timer = self.sound_timer;
+        let mut draw = false;
+        let mut executed = 0;
+        for _ in 0..cycles {
+            let pc = self.pc;
+            if self.emulate_cycle()? {
+                after(self, pc);
+                draw |= self.draw_flag;
+                executed += 1;
+            }
+        }
+        // Only the ROM changes the timer between ticks
+        let sound = if self.sound_timer != sound_timer {
+            Some(self.sound_timer)
+        } else {
+            None
+        };
+        self.tick_timers();
+        Ok(Frame {
+            draw,
+            sound,
+            cycles: executed,
+        })
+    }
+
     pub fn load_application(&mut self, filename: &str) {
         println!("Loading: {}", filename);
 
@@ -397,9 +761,9 @@ impl Chip8 {
 
         match fs::read(filename) {
             Ok(content) => {
-                if (4096 - 512) > filesize {
+                if filesize <= MAX_ROM_SIZE as u64 {
                     for i in 0..filesize {
-                        self.memory[i as usize + 512] = content[i as usize];
+                        self.memory[i as usize + PROGRAM_START] = content[i as usize];
                     }
                 } else {
                     panic!("Error: ROM too big for memory");
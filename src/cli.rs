@@ -0,0 +1,339 @@
+use crate::config::Config;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: chipulator8 [command] [options] <file>
+
+Commands:
+    run <rom>              Run a ROM (the default when no command is given)
+    disasm <rom>           Print the disassembly of a ROM
+    asm <source>           Assemble a source file into a ROM
+    info <rom>             Print information about a ROM
+    test <rom>             Run a ROM without a window and print the final screen
+    compare <rom> <trace>  Run a ROM in lockstep with a reference trace and report
+                           the first difference
+    help                   Print this help
+
+Options:
+    -h, --help             Print this help
+    --config <file>        Read settings from <file> instead of the default config file
+    --print-config         Print the effective settings and exit
+    --debug                Start paused with a debugger reading commands from the terminal
+    --symbols <file>       Read labels from <file> (default: the ROM's name with .sym)
+    --frontend <name>      Where to show the display: sdl (a window) or terminal
+    --charset <charset>    Characters the terminal frontend uses: halfblock, braille
+    --graphics <protocol>  Terminal bitmap graphics instead of characters: sixel, kitty
+    --scale <n>            Size of a CHIP-8 pixel in window pixels
+    --fullscreen           Start in fullscreen mode
+    --integer-scaling      Only scale the display by whole multiples
+    --persistence <f>      Phosphor afterglow, the brightness kept per frame (0.0-0.9)
+    --vsync                Present frames on the monitor's vertical blank
+    --stats                Show frame rate, speed and quirks on screen
+    --display-wait         Draw at most one sprite per 60 Hz frame to reduce flicker
+    --no-display-wait      Draw sprites right away, even with the vip quirks
+    --speed <n>            Instructions executed per second
+    --quirks <preset>      Quirks preset: default, vip, schip
+    --seed <n>             Seed the random number generator for reproducible runs
+    --stack-depth <n>      Return addresses the stack holds (default: 12 for vip, else 16)
+    --out-of-bounds <mode> Memory accesses past 4 KiB: wrap, fault
+    --volume <v>           Beeper volume from 0.0 to 1.0
+    --mute                 Start with the beeper muted
+    --no-audio             Don't open an audio device
+    --visual-beep          Flash the window border while the beeper sounds
+    --waveform <waveform>  Beeper waveform: square, sine, triangle, noise
+    --frequency <hz>       Beeper tone frequency, at least 20
+    --palette <palette>    Colour palette: classic, amber, green, lcd, high-contrast,
+                           colorblind or a list of #RRGGBB colours (foreground first)
+    --layout <layout>      Key layout: scancode, qwerty, qwertz, azerty, numpad
+    --trace <file>         Log every executed instruction to <file>
+    --trace-range <range>  Only log instructions in a hex address range like 200-2FF
+    --trace-ring <n>       Only log the last <n> instructions, when a fault occurs
+    --profile <file>       Count executed instructions and write a report to <file> on exit
+    --heatmap <file>       Write an image of how often each address ran to <file> (PPM)
+    -o, --output <file>    Where asm writes the ROM (default: source with .ch8 extension)
+    --frames <n>           Number of 60 Hz frames test runs for (default: 600)";
+
+pub enum Command {
+    Run(String),
+    Disasm(String),
+    Asm { source: String, output: PathBuf },
+    Info(String),
+    Test { rom: String, frames: u32 },
+    Compare { rom: String, reference: PathBuf },
+    Help,
+}
+
+/// Command line arguments. Settings given here take precedence over the
+/// config file.
+pub struct Args {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+    pub print_config: bool,
+    pub debug: bool,
+    /// Symbol file given instead of the one next to the ROM
+    pub symbols: Option<PathBuf>,
+    frontend: Option<String>,
+    charset: Option<String>,
+    graphics: Option<String>,
+    scale: Option<u32>,
+    fullscreen: bool,
+    integer_scaling: bool,
+    persistence: Option<f32>,
+    vsync: bool,
+    stats: bool,
+    display_wait: Option<bool>,
+    speed: Option<u32>,
+    quirks: Option<String>,
+    seed: Option<u64>,
+    stack_depth: Option<usize>,
+    out_of_bounds: Option<String>,
+    volume: Option<f32>,
+    mute: bool,
+    no_audio: bool,
+    visual_beep: bool,
+    waveform: Option<String>,
+    frequency: Option<f32>,
+    palette: Option<String>,
+    layout: Option<String>,
+    trace: Option<PathBuf>,
+    trace_range: Option<String>,
+    trace_ring: Option<usize>,
+    profile: Option<PathBuf>,
+    heatmap: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut args = args.peekable();
+        let name = match args.peek().map(|arg| arg.as_str()) {
+            Some(name @ "run")
+            | Some(name @ "disasm")
+            | Some(name @ "asm")
+            | Some(name @ "info")
+            | Some(name @ "test")
+            | Some(name @ "compare")
+            | Some(name @ "help") => {
+                let name = name.to_string();
+                args.next();
+                name
+            }
+            _ => "run".to_string(),
+        };
+
+        let mut parsed = Args {
+            command: Command::Help,
+            config: None,
+            print_config: false,
+            debug: false,
+            symbols: None,
+            frontend: None,
+            charset: None,
+            graphics: None,
+            scale: None,
+            fullscreen: false,
+            integer_scaling: false,
+            persistence: None,
+            vsync: false,
+            stats: false,
+            display_wait: None,
+            speed: None,
+            quirks: None,
+            seed: None,
+            stack_depth: None,
+            out_of_bounds: None,
+            volume: None,
+            mute: false,
+            no_audio: false,
+            visual_beep: false,
+            waveform: None,
+            frequency: None,
+            palette: None,
+            layout: None,
+            trace: None,
+            trace_range: None,
+            trace_ring: None,
+            profile: None,
+            heatmap: None,
+        };
+        let mut file = None;
+        let mut reference = None;
+        let mut output = None;
+        let mut frames = 600;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(parsed),
+                "--config" => parsed.config = Some(PathBuf::from(value()?)),
+                "--print-config" => parsed.print_config = true,
+                "--debug" => parsed.debug = true,
+                "--symbols" => parsed.symbols = Some(PathBuf::from(value()?)),
+                "--frontend" => parsed.frontend = Some(value()?),
+                "--charset" => parsed.charset = Some(value()?),
+                "--graphics" => parsed.graphics = Some(value()?),
+                "--scale" => parsed.scale = Some(parse_number(&arg, &value()?)?),
+                "--fullscreen" => parsed.fullscreen = true,
+                "--integer-scaling" => parsed.integer_scaling = true,
+                "--persistence" => parsed.persistence = Some(parse_number(&arg, &value()?)?),
+                "--vsync" => parsed.vsync = true,
+                "--stats" => parsed.stats = true,
+                "--display-wait" => parsed.display_wait = Some(true),
+                "--no-display-wait" => parsed.display_wait = Some(false),
+                "--speed" => parsed.speed = Some(parse_number(&arg, &value()?)?),
+                "--quirks" => parsed.quirks = Some(value()?),
+                "--seed" => parsed.seed = Some(parse_number(&arg, &value()?)?),
+                "--stack-depth" => parsed.stack_depth = Some(parse_number(&arg, &value()?)?),
+                "--out-of-bounds" => parsed.out_of_bounds = Some(value()?),
+                "--volume" => parsed.volume = Some(parse_number(&arg, &value()?)?),
+                "--mute" => parsed.mute = true,
+                "--no-audio" => parsed.no_audio = true,
+                "--visual-beep" => parsed.visual_beep = true,
+                "--waveform" => parsed.waveform = Some(value()?),
+                "--frequency" => parsed.frequency = Some(parse_number(&arg, &value()?)?),
+                "--palette" => parsed.palette = Some(value()?),
+                "--layout" => parsed.layout = Some(value()?),
+                "--trace" => parsed.trace = Some(PathBuf::from(value()?)),
+                "--trace-range" => parsed.trace_range = Some(value()?),
+                "--trace-ring" => parsed.trace_ring = Some(parse_number(&arg, &value()?)?),
+                "--profile" => parsed.profile = Some(PathBuf::from(value()?)),
+                "--heatmap" => parsed.heatmap = Some(PathBuf::from(value()?)),
+                "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+                "--frames" => frames = parse_number(&arg, &value()?)?,
+                _ if arg.starts_with('-') => {
+                    return Err(format!("Unknown option: {}\n\n{}", arg, USAGE))
+                }
+                _ if file.is_none() => file = Some(arg),
+                _ if name == "compare" && reference.is_none() => reference = Some(arg),
+                _ => return Err(format!("Unexpected argument: {}\n\n{}", arg, USAGE)),
+            }
+        }
+
+        parsed.command = match (name.as_str(), file) {
+            ("help", _) => Command::Help,
+            // Printing the config doesn't need a ROM, but takes its overrides into account
+            ("run", None) if parsed.print_config => Command::Help,
+            ("run", Some(rom)) => Command::Run(rom),
+            ("disasm", Some(rom)) => Command::Disasm(rom),
+            ("asm", Some(source)) => Command::Asm {
+                output: output.unwrap_or_else(|| PathBuf::from(&source).with_extension("ch8")),
+                source,
+            },
+            ("info", Some(rom)) => Command::Info(rom),
+            ("test", Some(rom)) => Command::Test { rom, frames },
+            ("compare", Some(rom)) => match reference {
+                Some(reference) => Command::Compare {
+                    rom,
+                    reference: PathBuf::from(reference),
+                },
+                None => return Err(USAGE.to_string()),
+            },
+            (_, None) => return Err(USAGE.to_string()),
+            (name, Some(_)) => return Err(format!("Unknown command: {}\n\n{}", name, USAGE)),
+        };
+        Ok(parsed)
+    }
+
+    /// Returns the ROM the command operates on, if any.
+    pub fn rom(&self) -> Option<&str> {
+        match &self.command {
+            Command::Run(rom) | Command::Disasm(rom) | Command::Info(rom) => Some(rom),
+            Command::Test { rom, .. } | Command::Compare { rom, .. } => Some(rom),
+            Command::Asm { .. } | Command::Help => None,
+        }
+    }
+
+    /// Overrides the settings in `config` with the ones given on the command line.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(frontend) = &self.frontend {
+            config.display.frontend = frontend.clone();
+        }
+        if let Some(charset) = &self.charset {
+            config.terminal.charset = charset.clone();
+        }
+        if let Some(graphics) = &self.graphics {
+            config.terminal.graphics = graphics.clone();
+        }
+        if let Some(scale) = self.scale {
+            config.display.scale = scale;
+        }
+        if self.fullscreen {
+            config.display.fullscreen = true;
+        }
+        if self.integer_scaling {
+            config.display.integer_scaling = true;
+        }
+        if let Some(persistence) = self.persistence {
+            config.display.persistence = persistence;
+        }
+        if self.vsync {
+            config.display.vsync = true;
+        }
+        if self.stats {
+            config.display.stats = true;
+        }
+        if self.display_wait.is_some() {
+            config.emulation.display_wait = self.display_wait;
+        }
+        if let Some(speed) = self.speed {
+            config.emulation.speed = speed;
+        }
+        if let Some(quirks) = &self.quirks {
+            config.emulation.quirks = quirks.clone();
+        }
+        if self.seed.is_some() {
+            config.emulation.seed = self.seed;
+        }
+        if self.stack_depth.is_some() {
+            config.emulation.stack_depth = self.stack_depth;
+        }
+        if let Some(out_of_bounds) = &self.out_of_bounds {
+            config.emulation.out_of_bounds = out_of_bounds.clone();
+        }
+        if let Some(volume) = self.volume {
+            config.audio.volume = volume;
+        }
+        if self.mute {
+            config.audio.mute = true;
+        }
+        if self.no_audio {
+            config.audio.enabled = false;
+        }
+        if self.visual_beep {
+            config.display.visual_beep = true;
+        }
+        if let Some(waveform) = &self.waveform {
+            config.audio.waveform = waveform.clone();
+        }
+        if let Some(frequency) = self.frequency {
+            config.audio.frequency = frequency;
+        }
+        if let Some(palette) = &self.palette {
+            config.display.palette = palette.clone();
+        }
+        if let Some(layout) = &self.layout {
+            config.keys.layout = layout.clone();
+        }
+        if self.trace.is_some() {
+            config.trace.file = self.trace.clone();
+        }
+        if self.trace_range.is_some() {
+            config.trace.range = self.trace_range.clone();
+        }
+        if let Some(ring) = self.trace_ring {
+            config.trace.ring = ring;
+        }
+        if self.profile.is_some() {
+            config.profile.report = self.profile.clone();
+        }
+        if self.heatmap.is_some() {
+            config.profile.heatmap = self.heatmap.clone();
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))
+}
@@ -0,0 +1,340 @@
+use crate::chip8::{Chip8, Fault};
+use crate::disasm::disassemble;
+use crate::frame_cycles;
+use crate::symbols::Symbols;
+
+/// One instruction of a reference trace. Fields the trace doesn't give are
+/// not compared.
+#[derive(Default)]
+struct Step {
+    /// Line number in the trace file
+    line: usize,
+    /// Number of the instruction in the run, counting from 1
+    cycle: Option<u64>,
+    pc: Option<u16>,
+    op: Option<u16>,
+    v: Option<[u8; 16]>,
+    i: Option<u16>,
+    sp: Option<u16>,
+    dt: Option<u8>,
+    st: Option<u8>,
+    stack: Option<Vec<u16>>,
+    writes: Option<Vec<(u16, u8)>>,
+}
+
+/// Runs `chip` in lockstep with `reference`, a trace in the format written
+/// by `trace::Tracer`, executing one instruction per line and ticking the
+/// timers at the end of every 60 Hz frame of `speed` instructions per
+/// second. Prints the first instruction whose results differ from the
+/// reference, located with `symbols`.
+///
+/// Only `pc` and `op` are needed, other emulators' logs can leave out any
+/// of the other fields or add ones that are ignored, and may write values
+/// with "0x". Once `stack` or `w` appear in a trace, lines without them mean
+/// an empty stack or no writes. When lines have a `cycle`, instructions the
+/// trace leaves out, like those outside `--trace-range` or before a
+/// `--trace-ring`, are run without being compared.
+pub fn compare(
+    chip: &mut Chip8,
+    reference: &str,
+    speed: u32,
+    symbols: &Symbols,
+) -> Result<(), String> {
+    let steps = parse(reference)?;
+    let mut cycles = 0;
+    let mut executed = 0;
+    for (index, step) in steps.iter().enumerate() {
+        let location = format!("Step {} (line {})", index + 1, step.line);
+        let cycle = step.cycle.unwrap_or(executed + 1);
+        if cycle <= executed {
+            return Err(format!(
+                "Line {}: cycle {} comes after cycle {}",
+                step.line, cycle, executed
+            ));
+        }
+        // Instructions left out of the trace run without being compared
+        while executed + 1 < cycle {
+            if let Err(fault) = execute(chip, &mut cycles, speed) {
+                println!("{}: {} at cycle {}", location, fault, executed + 1);
+                return Err(format!("Traces diverge at step {}", index + 1));
+            }
+            executed += 1;
+            end_cycle(chip, &mut cycles, speed);
+        }
+        let pc = chip.pc;
+        if let Some(expected) = step.pc {
+            if pc != expected {
+                println!(
+                    "{}: pc is {}, reference has {}",
+                    location,
+                    symbols.format(pc),
+                    symbols.format(expected)
+                );
+                return Err(format!("Traces diverge at step {}", index + 1));
+            }
+        }
+        if let Err(fault) = execute(chip, &mut cycles, speed) {
+            println!("{}: {}", location, fault);
+            return Err(format!("Traces diverge at step {}", index + 1));
+        }
+        executed += 1;
+        let differences = differences(chip, step);
+        if !differences.is_empty() {
+            println!(
+                "{}: {}: {:04X}  {}",
+                location,
+                symbols.format(pc),
+                chip.opcode,
+                disassemble(chip.opcode, symbols)
+            );
+            for difference in differences {
+                println!("    {}", difference);
+            }
+            return Err(format!("Traces diverge at step {}", index + 1));
+        }
+        end_cycle(chip, &mut cycles, speed);
+    }
+    println!("All {} steps match the reference", steps.len());
+    Ok(())
+}
+
+/// Executes the next instruction. A DXYN waiting for the vertical blank
+/// isn't in traces, but uses up cycles of the frame.
+fn execute(chip: &mut Chip8, cycles: &mut u32, speed: u32) -> Result<(), Fault> {
+    while !chip.emulate_cycle()? {
+        end_cycle(chip, cycles, speed);
+    }
+    Ok(())
+}
+
+/// Counts a cycle of the current frame, ticking the timers at its end.
+/// Traces show the state before the timers tick.
+fn end_cycle(chip: &mut Chip8, cycles: &mut u32, speed: u32) {
+    *cycles += 1;
+    if *cycles == frame_cycles(speed, chip.frame) {
+        chip.tick_timers();
+        *cycles = 0;
+    }
+}
+
+/// Describes every way the state of `chip` differs from `step`.
+fn differences(chip: &Chip8, step: &Step) -> Vec<String> {
+    let mut differences = Vec::new();
+    let mut check = |name: &str, actual: String, expected: Option<String>| {
+        if let Some(expected) = expected {
+            if actual != expected {
+                differences.push(format!(
+                    "{} is {}, reference has {}",
+                    name, actual, expected
+                ));
+            }
+        }
+    };
+    check(
+        "op",
+        format!("{:04X}", chip.opcode),
+        step.op.map(|op| format!("{:04X}", op)),
+    );
+    for register in 0..16 {
+        check(
+            &format!("V{:X}", register),
+            format!("{:02X}", chip.v[register]),
+            step.v.map(|v| format!("{:02X}", v[register])),
+        );
+    }
+    check(
+        "I",
+        format!("{:04X}", chip.i),
+        step.i.map(|i| format!("{:04X}", i)),
+    );
+    check(
+        "sp",
+        format!("{:X}", chip.sp),
+        step.sp.map(|sp| format!("{:X}", sp)),
+    );
+    check(
+        "delay timer",
+        format!("{:02X}", chip.delay_timer),
+        step.dt.map(|dt| format!("{:02X}", dt)),
+    );
+    check(
+        "sound timer",
+        format!("{:02X}", chip.sound_timer),
+        step.st.map(|st| format!("{:02X}", st)),
+    );
+    check(
+        "stack",
+        format_stack(chip.stack()),
+        step.stack.as_ref().map(|stack| format_stack(stack)),
+    );
+    check(
+        "memory writes",
+        format_writes(
+            &chip
+                .last_writes
+                .iter()
+                .map(|write| (write.address, write.value))
+                .collect::<Vec<_>>(),
+        ),
+        step.writes.as_ref().map(|writes| format_writes(writes)),
+    );
+    differences
+}
+
+fn format_stack(stack: &[u16]) -> String {
+    let addresses: Vec<String> = stack.iter().map(|a| format!("{:04X}", a)).collect();
+    format!("[{}]", addresses.join(", "))
+}
+
+fn format_writes(writes: &[(u16, u8)]) -> String {
+    let writes: Vec<String> = writes
+        .iter()
+        .map(|(address, value)| format!("{:04X}:{:02X}", address, value))
+        .collect();
+    format!("[{}]", writes.join(", "))
+}
+
+fn parse(reference: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for (index, line) in reference.lines().enumerate() {
+        let line_number = index + 1;
+        let content = line.split(';').next().unwrap().trim();
+        if content.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("Line {}: {}", line_number, message);
+        let mut step = Step {
+            line: line_number,
+            ..Step::default()
+        };
+        for token in content.split_whitespace() {
+            let (key, value) = match token.split_once('=') {
+                Some(pair) => pair,
+                None => return Err(error(format!("Expected key=value: {}", token))),
+            };
+            let invalid = || error(format!("Invalid value for {}: {}", key, value));
+            // Other emulators' logs may write values with "0x"
+            let hex16 = |value: &str| {
+                u16::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| invalid())
+            };
+            let hex8 = |value: &str| {
+                u8::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| invalid())
+            };
+            match key.to_lowercase().as_str() {
+                "cycle" => step.cycle = Some(value.parse().map_err(|_| invalid())?),
+                "pc" => step.pc = Some(hex16(value)?),
+                "op" => step.op = Some(hex16(value)?),
+                "i" => step.i = Some(hex16(value)?),
+                "sp" => step.sp = Some(hex16(value)?),
+                "dt" => step.dt = Some(hex8(value)?),
+                "st" => step.st = Some(hex8(value)?),
+                "v" => {
+                    let value = value.trim_start_matches("0x");
+                    if value.len() != 32 || !value.is_ascii() {
+                        return Err(invalid());
+                    }
+                    let mut v = [0; 16];
+                    for (register, v) in v.iter_mut().enumerate() {
+                        *v = hex8(&value[register * 2..register * 2 + 2])?;
+                    }
+                    step.v = Some(v);
+                }
+                "stack" => {
+                    step.stack = Some(value.split(',').map(hex16).collect::<Result<_, _>>()?)
+                }
+                "w" => {
+                    let writes = value
+                        .split(',')
+                        .map(|write| match write.split_once(':') {
+                            Some((address, value)) => Ok((hex16(address)?, hex8(value)?)),
+                            None => Err(invalid()),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    step.writes = Some(writes);
+                }
+                // Anything else is ignored
+                _ => {}
+            }
+        }
+        if step.pc.is_none() {
+            return Err(error("Missing pc".to_string()));
+        }
+        steps.push(step);
+    }
+
+    let has_stack = steps.iter().any(|step| step.stack.is_some());
+    let has_writes = steps.iter().any(|step| step.writes.is_some());
+    for step in steps.iter_mut() {
+        if has_stack && step.stack.is_none() {
+            step.stack = Some(Vec::new());
+        }
+        if has_writes && step.writes.is_none() {
+            step.writes = Some(Vec::new());
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let steps = parse(
+            "cycle=1 pc=0200 op=6A02 v=00000000000000000000020000000000 i=0300 sp=1 \
+             dt=0A st=FF stack=0202,0310 w=0300:01,0301:02 ; LD VA, 0x02",
+        )
+        .unwrap();
+        let step = &steps[0];
+        assert_eq!(step.line, 1);
+        assert_eq!(step.cycle, Some(1));
+        assert_eq!(step.pc, Some(0x200));
+        assert_eq!(step.op, Some(0x6A02));
+        let mut v = [0; 16];
+        v[0xA] = 2;
+        assert_eq!(step.v, Some(v));
+        assert_eq!(step.i, Some(0x300));
+        assert_eq!(step.sp, Some(1));
+        assert_eq!(step.dt, Some(0x0A));
+        assert_eq!(step.st, Some(0xFF));
+        assert_eq!(step.stack, Some(vec![0x202, 0x310]));
+        assert_eq!(step.writes, Some(vec![(0x300, 0x01), (0x301, 0x02)]));
+    }
+
+    #[test]
+    fn prefixes_and_missing_fields() {
+        let steps = parse(
+            "PC=0x200 Op=0x00E0 V=0x00000000000000000000000000000000\n\n\
+             ; comment\npc=202 stack=0x204 extra=1",
+        )
+        .unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].pc, Some(0x200));
+        assert_eq!(steps[0].op, Some(0x00E0));
+        assert_eq!(steps[0].v, Some([0; 16]));
+        assert_eq!(steps[0].i, None);
+        assert_eq!(steps[0].cycle, None);
+        // Once a trace has a stack, lines without one have an empty stack
+        assert_eq!(steps[0].stack, Some(Vec::new()));
+        assert_eq!(steps[0].writes, None);
+        assert_eq!(steps[1].line, 4);
+        assert_eq!(steps[1].stack, Some(vec![0x204]));
+    }
+
+    #[test]
+    fn rejected() {
+        let error = |reference: &str| parse(reference).err().unwrap();
+        assert_eq!(error("op=00E0"), "Line 1: Missing pc");
+        assert_eq!(error("pc=200\npc"), "Line 2: Expected key=value: pc");
+        assert_eq!(error("pc=10000"), "Line 1: Invalid value for pc: 10000");
+        assert_eq!(error("pc=200 dt=100"), "Line 1: Invalid value for dt: 100");
+        assert_eq!(error("pc=200 v=00"), "Line 1: Invalid value for v: 00");
+        assert_eq!(error("pc=200 w=0300"), "Line 1: Invalid value for w: 0300");
+        assert_eq!(error("pc=xyz"), "Line 1: Invalid value for pc: xyz");
+        assert_eq!(
+            error("cycle=A pc=200"),
+            "Line 1: Invalid value for cycle: A"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use crate::effects::MAX_RESOLUTION;
+use crate::gamepad::DEADZONE;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Settings read from `$XDG_CONFIG_HOME/chipulator8/config.toml`.
+///
+/// ```toml
+/// [display]
+/// scale = 10
+/// palette = "amber"
+///
+/// [emulation]
+/// speed = 700
+/// quirks = "vip"
+///
+/// [keys]
+/// layout = "qwertz"
+/// bindings = { "Space" = "5", "key:P" = "C" }
+///
+/// [gamepad]
+/// buttons = { "a" = "5", "rightshoulder" = "0" }
+/// axes = { "rightx-" = "4", "rightx+" = "6" }
+///
+/// [roms."invaders.rom".keys]
+/// bindings = { "Left" = "4", "Right" = "6" }
+/// ```
+///
+/// Any setting can be overridden for a single ROM in a `[roms."<file name>"]`
+/// table, which is merged over the global settings.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub display: DisplayConfig,
+    pub emulation: EmulationConfig,
+    pub audio: AudioConfig,
+    pub effects: EffectsConfig,
+    pub keys: KeysConfig,
+    pub gamepad: GamepadConfig,
+    pub terminal: TerminalConfig,
+    pub trace: TraceConfig,
+    pub profile: ProfileConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    /// Size of a CHIP-8 pixel in window pixels
+    pub scale: u32,
+    pub fullscreen: bool,
+    /// Only scale the display by whole multiples when the window is resized
+    pub integer_scaling: bool,
+    /// Fraction of its brightness a pixel keeps per frame after being turned
+    /// off, from 0.0, which disables the afterglow, to 0.9
+    pub persistence: f32,
+    /// Present frames on the monitor's vertical blank, so a frame never shows
+    /// half of one picture and half of the next
+    pub vsync: bool,
+    /// Name of a preset or comma separated "#RRGGBB" colours, see `palette::Palette`
+    pub palette: String,
+    /// Colour of set pixels as "#RRGGBB", replacing the palette's
+    pub foreground: Option<String>,
+    /// Colour of unset pixels as "#RRGGBB", replacing the palette's
+    pub background: Option<String>,
+    /// Flash a border around the window while the beeper sounds
+    pub visual_beep: bool,
+    /// Show frame rate, speed and quirks on screen
+    pub stats: bool,
+    /// "sdl" for a window or "terminal" to draw with text
+    pub frontend: String,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            scale: 20,
+            fullscreen: false,
+            integer_scaling: false,
+            persistence: 0.0,
+            vsync: false,
+            palette: "classic".to_string(),
+            foreground: None,
+            background: None,
+            visual_beep: false,
+            stats: false,
+            frontend: "sdl".to_string(),
+        }
+    }
+}
+
+/// CRT effects, see `effects::Effects`. All of them are off by default.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EffectsConfig {
+    /// Texels per CHIP-8 pixel the effects are rendered at, from 1 to 16
+    pub resolution: usize,
+    /// Scanline darkening from 0.0 to 1.0
+    pub scanlines: f32,
+    /// Pixel grid darkening from 0.0 to 1.0
+    pub grid: f32,
+    /// Glow around lit pixels from 0.0 to 1.0
+    pub bloom: f32,
+}
+
+impl Default for EffectsConfig {
+    fn default() -> Self {
+        EffectsConfig {
+            resolution: 4,
+            scanlines: 0.0,
+            grid: 0.0,
+            bloom: 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmulationConfig {
+    /// Instructions executed per second
+    pub speed: u32,
+    /// Quirks preset, see `chip8::Quirks`
+    pub quirks: String,
+    /// Seed for the random number generator, random if not set
+    pub seed: Option<u64>,
+    /// Draw at most one sprite per frame, by default only with the "vip" quirks
+    /// preset. Set either way, this overrides the preset.
+    pub display_wait: Option<bool>,
+    /// Speed multiplier while fast forwarding, 0 runs as fast as possible
+    pub fast_forward: u32,
+    /// Return addresses the stack holds, by default 12 for the "vip" quirks
+    /// preset and 16 otherwise
+    pub stack_depth: Option<usize>,
+    /// Memory accesses past 4 KiB "wrap" around or stop the ROM with a "fault"
+    pub out_of_bounds: String,
+}
+
+impl Default for EmulationConfig {
+    fn default() -> Self {
+        EmulationConfig {
+            speed: 500,
+            quirks: "default".to_string(),
+            seed: None,
+            display_wait: None,
+            fast_forward: 4,
+            stack_depth: None,
+            out_of_bounds: "wrap".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Whether to open an audio device at all
+    pub enabled: bool,
+    /// Beeper volume from 0.0 to 1.0
+    pub volume: f32,
+    pub mute: bool,
+    /// Tone frequency in Hz, at least 20. Tones above half the sample rate
+    /// of the audio device are lowered to that.
+    pub frequency: f32,
+    /// One of "square", "sine", "triangle" or "noise"
+    pub waveform: String,
+    /// Fade in time of the tone in milliseconds
+    pub attack_ms: f32,
+    /// Fade out time of the tone in milliseconds
+    pub release_ms: f32,
+    /// Don't sound for sound timer values below 2, as on the COSMAC VIP
+    pub vip_timing: bool,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            enabled: true,
+            volume: 0.25,
+            mute: false,
+            frequency: 240.0,
+            waveform: "square".to_string(),
+            attack_ms: 5.0,
+            release_ms: 5.0,
+            vip_timing: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Layout preset the bindings start from, see `keymap::Layout`
+    pub layout: String,
+    /// Host key name to CHIP-8 hex key
+    pub bindings: HashMap<String, String>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            layout: "scancode".to_string(),
+            bindings: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+    /// Stick values within +-deadzone count as centered, from 0 to 32766
+    pub deadzone: i16,
+    /// SDL button name to CHIP-8 hex key
+    pub buttons: HashMap<String, String>,
+    /// SDL axis name followed by + or - to CHIP-8 hex key
+    pub axes: HashMap<String, String>,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        GamepadConfig {
+            deadzone: DEADZONE,
+            buttons: HashMap::new(),
+            axes: HashMap::new(),
+        }
+    }
+}
+
+/// Settings of the terminal frontend, see `terminal`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TerminalConfig {
+    /// "halfblock" or "braille"
+    pub charset: String,
+    /// Draw bitmaps instead of characters with "sixel" or "kitty" graphics,
+    /// or "none"
+    pub graphics: String,
+    /// Size of a CHIP-8 pixel in terminal pixels with graphics. The kitty
+    /// terminal scales the picture itself, assuming 8x16 pixel cells.
+    pub scale: usize,
+}
+
+impl Default for TerminalConfig {
+    fn default() -> Self {
+        TerminalConfig {
+            charset: "halfblock".to_string(),
+            graphics: "none".to_string(),
+            scale: 8,
+        }
+    }
+}
+
+/// Instruction trace logging, see `trace::Tracer`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceConfig {
+    /// File the trace is written to, tracing is off if not set
+    pub file: Option<PathBuf>,
+    /// Only log instructions in this hex address range, like "200-2FF"
+    pub range: Option<String>,
+    /// Keep only the last `ring` instructions and write them out when a
+    /// fault stops the ROM, 0 logs everything as it runs
+    pub ring: usize,
+}
+
+/// Execution profiling, see `profile::Profile`. The ROM is profiled when
+/// either file is set.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// File the report is written to on exit
+    pub report: Option<PathBuf>,
+    /// PPM image of how often each byte of memory was executed
+    pub heatmap: Option<PathBuf>,
+    /// Entries in each table of the report
+    pub top: usize,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        ProfileConfig {
+            report: None,
+            heatmap: None,
+            top: 20,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file at `path`, or from the default location if no
+    /// path is given, and applies the overrides for `rom`. A missing file at
+    /// the default location is not an error and yields the default settings.
+    pub fn load(path: Option<&Path>, rom: Option<&str>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&content, rom).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(content: &str, rom: Option<&str>) -> Result<Config, String> {
+        let mut value: Value = toml::from_str(content).map_err(|e| e.to_string())?;
+        let roms = match value.as_table_mut().and_then(|t| t.remove("roms")) {
+            Some(Value::Table(roms)) => roms,
+            Some(_) => return Err("roms must be a table".to_string()),
+            None => Default::default(),
+        };
+        if let Some(overrides) = rom.and_then(|rom| roms.get(rom_name(rom))) {
+            merge(&mut value, overrides.clone());
+        }
+        value.try_into().map_err(|e| e.to_string())
+    }
+
+    /// Checks the settings whose type allows values that make no sense, after
+    /// the command line has been applied.
+    pub fn check(&self) -> Result<(), String> {
+        if self.display.scale == 0 {
+            return Err("Invalid scale: 0 (expected at least 1)".to_string());
+        }
+        if !(0.0..=0.9).contains(&self.display.persistence) {
+            return Err(format!(
+                "Invalid persistence: {} (expected 0.0-0.9)",
+                self.display.persistence
+            ));
+        }
+        if !(0..i16::MAX).contains(&self.gamepad.deadzone) {
+            return Err(format!(
+                "Invalid gamepad deadzone: {} (expected 0-{})",
+                self.gamepad.deadzone,
+                i16::MAX - 1
+            ));
+        }
+        // The highest frequency depends on the audio device, see `audio::open`
+        if !(20.0..=f32::MAX).contains(&self.audio.frequency) {
+            return Err(format!(
+                "Invalid audio frequency: {} (expected at least 20 Hz)",
+                self.audio.frequency
+            ));
+        }
+        let fractions = [
+            ("volume", self.audio.volume),
+            ("scanlines", self.effects.scanlines),
+            ("grid", self.effects.grid),
+            ("bloom", self.effects.bloom),
+        ];
+        for &(name, value) in &fractions {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("Invalid {}: {} (expected 0.0-1.0)", name, value));
+            }
+        }
+        // The effects are rendered on the CPU into a texture this many times
+        // the size of the display
+        if !(1..=MAX_RESOLUTION).contains(&self.effects.resolution) {
+            return Err(format!(
+                "Invalid effects resolution: {} (expected 1-{})",
+                self.effects.resolution, MAX_RESOLUTION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Renders the settings in the config file format.
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string(self).map_err(|e| e.to_string())
+    }
+}
+
+/// Returns the path of the config file following the XDG base directory spec.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("chipulator8").join("config.toml"))
+}
+
+/// Parses a colour given as "#RRGGBB" or "RRGGBB".
+pub fn parse_color(color: &str) -> Result<(u8, u8, u8), String> {
+    let hex = color.trim_start_matches('#');
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) if hex.len() == 6 => Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+        _ => Err(format!("Invalid colour: {}", color)),
+    }
+}
+
+/// Merges `overlay` into `base`, recursing into tables present in both.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn rom_name(rom: &str) -> &str {
+    Path::new(rom)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_tables() {
+        let mut base: Value = toml::from_str(
+            "[display]\nscale = 10\npalette = \"amber\"\n[keys.bindings]\nSpace = \"5\"",
+        )
+        .unwrap();
+        let overlay: Value =
+            toml::from_str("[display]\nscale = 4\n[keys.bindings]\nP = \"C\"").unwrap();
+        merge(&mut base, overlay);
+        assert_eq!(base["display"]["scale"].as_integer(), Some(4));
+        assert_eq!(base["display"]["palette"].as_str(), Some("amber"));
+        assert_eq!(base["keys"]["bindings"]["Space"].as_str(), Some("5"));
+        assert_eq!(base["keys"]["bindings"]["P"].as_str(), Some("C"));
+    }
+
+    #[test]
+    fn rom_overrides() {
+        let content = "[display]\nscale = 10\n\n\
+                       [emulation]\nspeed = 700\n\n\
+                       [roms.\"tetris.rom\".emulation]\nspeed = 300\n";
+        // Only the file name of the ROM is matched
+        let config = Config::parse(content, Some("roms/tetris.rom")).unwrap();
+        assert_eq!(config.emulation.speed, 300);
+        assert_eq!(config.display.scale, 10);
+        let config = Config::parse(content, Some("roms/tank.rom")).unwrap();
+        assert_eq!(config.emulation.speed, 700);
+        let config = Config::parse(content, None).unwrap();
+        assert_eq!(config.emulation.speed, 700);
+        // Settings missing everywhere keep their defaults
+        assert_eq!(config.emulation.quirks, "default");
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#FF8000"), Ok((0xFF, 0x80, 0x00)));
+        assert_eq!(parse_color("00ff7f"), Ok((0x00, 0xFF, 0x7F)));
+        assert_eq!(parse_color("#FFF"), Err("Invalid colour: #FFF".to_string()));
+        assert_eq!(
+            parse_color("#GG0000"),
+            Err("Invalid colour: #GG0000".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let error = Config::parse("[display]\nscael = 10", None).unwrap_err();
+        assert!(error.contains("unknown field `scael`"), "{}", error);
+        let error =
+            Config::parse("[roms.\"tank.rom\".display]\nscael = 10", Some("tank.rom")).unwrap_err();
+        assert!(error.contains("unknown field `scael`"), "{}", error);
+        assert!(Config::parse("[roms]\n", Some("tank.rom")).is_ok());
+        assert!(Config::parse("roms = 1", None).is_err());
+    }
+}
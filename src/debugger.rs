@@ -0,0 +1,674 @@
+use crate::chip8::{Chip8, Fault, Frame};
+use crate::disasm::disassemble;
+use crate::expr::{parse_number, Expr};
+use crate::symbols::Symbols;
+use crate::trace::Tracer;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const HELP: &str = "Commands:
+    c, continue                 Resume execution
+    s, step [n]                 Execute n instructions (default: 1)
+    p, pause                    Stop execution
+    b, break <addr> [if <expr>] Stop before the instruction at addr, if the
+                                condition is true
+    w, watch <addr>[-<end>] [r|w|rw]
+                                Stop after an instruction reads and/or writes
+                                memory in a range (default: w)
+    d, delete <n>               Delete breakpoint or watchpoint n
+    i, info                     List breakpoints and watchpoints
+    display [expr]              Show the value of expr whenever execution
+                                stops, without expr list the expressions
+    undisplay <n>               Remove expression n from the display list
+    print <expr>                Show the value of expr
+    r, regs                     Show the registers
+    bt, backtrace               Show the call stack
+    m, mem <addr> [len]         Show len bytes of memory (default: 64), with
+                                the program counter, I, the font and bytes
+                                written since execution stopped highlighted
+    set <reg> <expr>            Set V0-VF, I, PC, DT or ST while paused
+    poke <addr> <byte>...       Write bytes to memory while paused
+    h, help                     Show this help
+    q, quit                     Quit the emulator
+
+Numbers are decimal unless they start with 0x, e.g. 0x2A4. Addresses can also
+be labels from the symbol file. Expressions are over V0-VF, I, PC, SP, DT, ST,
+key[n], mem[addr] and labels with C operators, e.g. V3 == 0x10 && I > 0x300.";
+
+/// Where execution stops.
+enum Stop {
+    /// Before the instruction at an address is executed, if the condition
+    /// is true
+    Break(u16, Option<Expr>),
+    /// After an instruction accessed memory in an address range
+    Watch {
+        start: u16,
+        end: u16,
+        read: bool,
+        write: bool,
+    },
+}
+
+/// An interactive debugger reading commands from stdin while the ROM runs in
+/// the window. It takes over running frames, executing instructions one at a
+/// time so it can stop anywhere within a frame.
+pub struct Debugger {
+    /// Breakpoints and watchpoints with their numbers
+    stops: Vec<(usize, Stop)>,
+    next_id: usize,
+    /// Expressions shown whenever execution stops, with their numbers
+    displays: Vec<(usize, Expr)>,
+    next_display: usize,
+    paused: bool,
+    /// Instructions left to execute before pausing again, when stepping
+    steps: Option<u32>,
+    /// Don't stop at a breakpoint on the first instruction after resuming,
+    /// which would be the one execution stopped at
+    resuming: bool,
+    /// Instructions executed in the current frame
+    frame_cycles: u32,
+    /// Which bytes of memory were written since execution last stopped,
+    /// highlighted by `mem`
+    written: Vec<bool>,
+    symbols: Symbols,
+    input: Receiver<String>,
+    quit: bool,
+}
+
+impl Debugger {
+    /// Starts the debugger with execution paused.
+    pub fn new(chip: &Chip8, symbols: &Symbols) -> Self {
+        // Reading stdin blocks, so it happens on its own thread
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        println!("Debugger started, type \"help\" for a list of commands");
+        let debugger = Debugger {
+            stops: Vec::new(),
+            next_id: 1,
+            displays: Vec::new(),
+            next_display: 1,
+            paused: true,
+            steps: None,
+            resuming: false,
+            frame_cycles: 0,
+            written: vec![false; chip.memory.len()],
+            symbols: symbols.clone(),
+            input,
+            quit: false,
+        };
+        debugger.show_location(chip);
+        prompt();
+        debugger
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Stops execution at the current instruction.
+    pub fn pause(&mut self, chip: &Chip8) {
+        if !self.paused {
+            self.paused = true;
+            self.steps = None;
+            println!();
+            self.show_location(chip);
+            prompt();
+        }
+    }
+
+    /// Handles the commands typed since the last call. Returns false once
+    /// the user asked to quit.
+    pub fn poll(&mut self, chip: &mut Chip8) -> bool {
+        // Without stdin the window still works, just without commands
+        while let Ok(line) = self.input.try_recv() {
+            if let Err(e) = self.command(line.trim(), chip) {
+                println!("{}", e);
+            }
+            if self.paused {
+                prompt();
+            }
+        }
+        !self.quit
+    }
+
+    /// Runs what is left of the current frame, or until execution stops.
+    /// The timers tick once a frame's worth of instructions has run.
+    pub fn run_frame(
+        &mut self,
+        chip: &mut Chip8,
+        cycles: u32,
+        tracer: &mut Option<Tracer>,
+    ) -> Result<Frame, Fault> {
+        let sound_timer = chip.sound_timer;
+        let mut draw = false;
+        let mut instructions = 0;
+        while !self.paused && self.frame_cycles < cycles {
+            if self.steps == Some(0) {
+                self.stop(chip, None);
+                break;
+            }
+            if !self.resuming {
+                if let Some(reason) = self.breakpoint(chip) {
+                    self.stop(chip, Some(reason));
+                    break;
+                }
+            }
+
+            let pc = chip.pc;
+            let executed = match chip.emulate_cycle() {
+                Ok(executed) => executed,
+                Err(fault) => {
+                    if let Some(tracer) = tracer {
+                        tracer.fault(&fault);
+                    }
+                    self.stop(chip, Some(fault.to_string()));
+                    return Err(fault);
+                }
+            };
+            self.frame_cycles += 1;
+            // A DXYN waiting for the vertical blank doesn't count as a step,
+            // and stays past its breakpoint until it runs
+            if !executed {
+                continue;
+            }
+            self.resuming = false;
+            instructions += 1;
+            if let Some(tracer) = tracer {
+                tracer.record(chip, pc);
+            }
+            draw |= chip.draw_flag;
+            for write in &chip.last_writes {
+                self.written[write.address as usize] = true;
+            }
+            if let Some(steps) = &mut self.steps {
+                *steps -= 1;
+            }
+
+            let hits = self.watch_hits(chip, pc);
+            if !hits.is_empty() {
+                self.stop(chip, Some(hits.join("\n")));
+            }
+        }
+
+        let sound = if chip.sound_timer != sound_timer {
+            Some(chip.sound_timer)
+        } else {
+            None
+        };
+        if self.frame_cycles >= cycles {
+            chip.tick_timers();
+            self.frame_cycles = 0;
+        }
+        Ok(Frame {
+            draw,
+            sound,
+            cycles: instructions,
+        })
+    }
+
+    fn command(&mut self, line: &str, chip: &mut Chip8) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        let args: Vec<&str> = words.collect();
+        match command {
+            "c" | "continue" => self.resume(None),
+            "s" | "step" => {
+                let steps = match args.first() {
+                    Some(n) => number(n, "count")?,
+                    None => 1,
+                };
+                self.resume(Some(steps));
+            }
+            "p" | "pause" => self.pause(chip),
+            "b" | "break" => {
+                let usage = "Usage: break <addr> [if <expr>]";
+                let address = self.symbols.parse_address(args.first().ok_or(usage)?)?;
+                let condition = match args.get(1) {
+                    Some(&"if") => Some(Expr::parse(&args[2..].join(" "), &self.symbols)?),
+                    Some(_) => return Err(usage.to_string()),
+                    None => None,
+                };
+                let stop = Stop::Break(address, condition);
+                let description = describe(&stop, &self.symbols);
+                let id = self.add(stop);
+                println!("Breakpoint {}: {}", id, description);
+            }
+            "w" | "watch" => {
+                let range = args.first().ok_or("Usage: watch <addr>[-<end>] [r|w|rw]")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (
+                        self.symbols.parse_address(start)?,
+                        self.symbols.parse_address(end)?,
+                    ),
+                    None => {
+                        let address = self.symbols.parse_address(range)?;
+                        (address, address)
+                    }
+                };
+                if start > end {
+                    return Err(format!("Invalid range: {}", range));
+                }
+                let (read, write) = match args.get(1).copied().unwrap_or("w") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    mode => return Err(format!("Invalid mode: {} (expected r, w or rw)", mode)),
+                };
+                let stop = Stop::Watch {
+                    start,
+                    end,
+                    read,
+                    write,
+                };
+                let description = describe(&stop, &self.symbols);
+                let id = self.add(stop);
+                println!("Watchpoint {}: {}", id, description);
+            }
+            "d" | "delete" => {
+                let id = args.first().ok_or("Usage: delete <n>")?;
+                let id: usize = number(id, "number")?;
+                let before = self.stops.len();
+                self.stops.retain(|(stop_id, _)| *stop_id != id);
+                if self.stops.len() == before {
+                    return Err(format!("No breakpoint or watchpoint {}", id));
+                }
+            }
+            "i" | "info" => {
+                if self.stops.is_empty() {
+                    println!("No breakpoints or watchpoints");
+                }
+                for (id, stop) in &self.stops {
+                    println!("{}: {}", id, describe(stop, &self.symbols));
+                }
+            }
+            "display" => {
+                if args.is_empty() {
+                    self.show_displays(chip);
+                } else {
+                    let expr = Expr::parse(&args.join(" "), &self.symbols)?;
+                    let id = self.next_display;
+                    self.next_display += 1;
+                    println!("{}: {} = {}", id, expr, format_value(expr.eval(chip)));
+                    self.displays.push((id, expr));
+                }
+            }
+            "undisplay" => {
+                let id = args.first().ok_or("Usage: undisplay <n>")?;
+                let id: usize = number(id, "number")?;
+                let before = self.displays.len();
+                self.displays.retain(|(display_id, _)| *display_id != id);
+                if self.displays.len() == before {
+                    return Err(format!("No display expression {}", id));
+                }
+            }
+            "print" => {
+                let expr = Expr::parse(&args.join(" "), &self.symbols)?;
+                println!("{}", format_value(expr.eval(chip)));
+            }
+            "r" | "regs" => show_registers(chip),
+            "bt" | "backtrace" => show_call_stack(chip, &self.symbols),
+            "m" | "mem" => {
+                let address = self
+                    .symbols
+                    .parse_address(args.first().ok_or("Usage: mem <addr> [len]")?)?;
+                let len = match args.get(1) {
+                    Some(len) => number(len, "length")?,
+                    None => 64,
+                };
+                show_memory(chip, address, len, &self.written);
+            }
+            "set" => {
+                if !self.paused {
+                    return Err("Pause execution first".to_string());
+                }
+                let (register, value) = match args.split_first() {
+                    Some((register, value)) if !value.is_empty() => (register, value),
+                    _ => return Err("Usage: set <reg> <expr>".to_string()),
+                };
+                let value = Expr::parse(&value.join(" "), &self.symbols)?.eval(chip)?;
+                set_register(chip, register, value)?;
+                show_registers(chip);
+            }
+            "poke" => {
+                if !self.paused {
+                    return Err("Pause execution first".to_string());
+                }
+                let usage = "Usage: poke <addr> <byte>...";
+                let address = self.symbols.parse_address(args.first().ok_or(usage)?)?;
+                if args.len() < 2 {
+                    return Err(usage.to_string());
+                }
+                let bytes = args[1..]
+                    .iter()
+                    .map(|byte| number(byte, "byte"))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                if address as usize + bytes.len() > chip.memory.len() {
+                    return Err(format!("Past the end of memory: {:03X}", address));
+                }
+                for (offset, &byte) in bytes.iter().enumerate() {
+                    chip.memory[address as usize + offset] = byte;
+                    self.written[address as usize + offset] = true;
+                }
+                show_memory(chip, address, bytes.len() as u16, &self.written);
+            }
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => self.quit = true,
+            _ => return Err(format!("Unknown command: {} (try \"help\")", command)),
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, stop: Stop) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stops.push((id, stop));
+        id
+    }
+
+    fn resume(&mut self, steps: Option<u32>) {
+        self.paused = false;
+        self.written.fill(false);
+        self.steps = steps;
+        self.resuming = true;
+    }
+
+    /// Pauses execution, printing why and where.
+    fn stop(&mut self, chip: &Chip8, reason: Option<String>) {
+        self.paused = true;
+        self.steps = None;
+        if let Some(reason) = reason {
+            println!("{}", reason);
+        }
+        self.show_location(chip);
+        prompt();
+    }
+
+    /// Returns why execution stops at a breakpoint before the instruction
+    /// at the program counter, if it does. Conditions that can't be
+    /// evaluated stop execution as well.
+    fn breakpoint(&self, chip: &Chip8) -> Option<String> {
+        self.stops.iter().find_map(|(id, stop)| match stop {
+            Stop::Break(address, condition) if *address == chip.pc => match condition {
+                None => Some(format!("Breakpoint {}", id)),
+                Some(condition) => match condition.eval(chip) {
+                    Ok(0) => None,
+                    Ok(_) => Some(format!("Breakpoint {}: {}", id, condition)),
+                    Err(e) => Some(format!("Breakpoint {}: {}: {}", id, condition, e)),
+                },
+            },
+            _ => None,
+        })
+    }
+
+    /// Describes the memory accesses of the instruction just executed from
+    /// `pc` that hit a watchpoint.
+    fn watch_hits(&self, chip: &Chip8, pc: u16) -> Vec<String> {
+        let mut hits = Vec::new();
+        for (id, stop) in &self.stops {
+            if let Stop::Watch {
+                start,
+                end,
+                read,
+                write,
+            } = *stop
+            {
+                let watched = |address: u16| address >= start && address <= end;
+                let mut accesses = Vec::new();
+                if read {
+                    for &address in chip.last_reads.iter().filter(|&&a| watched(a)) {
+                        accesses.push(format!(
+                            "read {:03X} = {:02X}",
+                            address, chip.memory[address as usize]
+                        ));
+                    }
+                }
+                if write {
+                    for access in chip.last_writes.iter().filter(|w| watched(w.address)) {
+                        accesses.push(format!(
+                            "wrote {:03X}: {:02X} -> {:02X}",
+                            access.address, access.old, access.value
+                        ));
+                    }
+                }
+                if !accesses.is_empty() {
+                    hits.push(format!(
+                        "Watchpoint {}: {}: {:04X}  {}\n    {}",
+                        id,
+                        self.symbols.format(pc),
+                        chip.opcode,
+                        disassemble(chip.opcode, &self.symbols),
+                        accesses.join("\n    ")
+                    ));
+                }
+            }
+        }
+        hits
+    }
+
+    /// Prints the instruction execution stopped at and the display list.
+    fn show_location(&self, chip: &Chip8) {
+        let opcode = opcode_at(chip, chip.pc);
+        println!(
+            "{}: {:04X}  {}",
+            self.symbols.format(chip.pc),
+            opcode,
+            disassemble(opcode, &self.symbols)
+        );
+        self.show_displays(chip);
+    }
+
+    fn show_displays(&self, chip: &Chip8) {
+        for (id, expr) in &self.displays {
+            println!("{}: {} = {}", id, expr, format_value(expr.eval(chip)));
+        }
+    }
+}
+
+fn prompt() {
+    print!("(debug) ");
+    let _ = io::stdout().flush();
+}
+
+fn opcode_at(chip: &Chip8, address: u16) -> u16 {
+    let byte = |address: u16| chip.memory[address as usize % chip.memory.len()] as u16;
+    byte(address) << 8 | byte(address.wrapping_add(1))
+}
+
+/// Formats the value of an expression in decimal and hex.
+fn format_value(value: Result<i64, String>) -> String {
+    match value {
+        Ok(value) if value < 0 => format!("{}", value),
+        Ok(value) => format!("{} (0x{:X})", value, value),
+        Err(e) => e,
+    }
+}
+
+/// Parses `word` as a number of type `T`, decimal unless it starts with
+/// "0x", naming it `what` in the error.
+fn number<T: TryFrom<i64>>(word: &str, what: &str) -> Result<T, String> {
+    parse_number(word)
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| format!("Invalid {}: {}", what, word))
+}
+
+fn describe(stop: &Stop, symbols: &Symbols) -> String {
+    match *stop {
+        Stop::Break(address, None) => format!("break at {}", symbols.format(address)),
+        Stop::Break(address, Some(ref condition)) => {
+            format!("break at {} if {}", symbols.format(address), condition)
+        }
+        Stop::Watch {
+            start,
+            end,
+            read,
+            write,
+        } => {
+            let mode = match (read, write) {
+                (true, true) => "read/write",
+                (true, false) => "read",
+                _ => "write",
+            };
+            if start == end {
+                format!("watch {} of {}", mode, symbols.format(start))
+            } else {
+                format!(
+                    "watch {} of {}-{}",
+                    mode,
+                    symbols.format(start),
+                    symbols.format(end)
+                )
+            }
+        }
+    }
+}
+
+fn show_registers(chip: &Chip8) {
+    println!(
+        "PC {:03X}  I {:03X}  SP {:X}  DT {:02X}  ST {:02X}",
+        chip.pc, chip.i, chip.sp, chip.delay_timer, chip.sound_timer
+    );
+    for row in 0..2 {
+        let registers: Vec<String> = (row * 8..row * 8 + 8)
+            .map(|r| format!("V{:X} {:02X}", r, chip.v[r]))
+            .collect();
+        println!("{}", registers.join("  "));
+    }
+}
+
+/// Prints the subroutine calls leading to the current instruction, the
+/// innermost first, with the subroutine each of them is in.
+fn show_call_stack(chip: &Chip8, symbols: &Symbols) {
+    let stack = chip.stack();
+    // A subroutine starts at the address called, the outermost code at the
+    // start of the program
+    let subroutine = |frame: usize| {
+        let address = match frame {
+            0 => 0x200,
+            _ => opcode_at(chip, stack[frame - 1]) & 0x0FFF,
+        };
+        match symbols.name(address) {
+            Some(name) => name.to_string(),
+            None => format!("{:03X}", address),
+        }
+    };
+    println!(
+        "#0  {} in {}",
+        symbols.format(chip.pc),
+        subroutine(stack.len())
+    );
+    for (frame, &call) in stack.iter().enumerate().rev() {
+        println!(
+            "#{:<2} {} in {}",
+            stack.len() - frame,
+            symbols.format(call),
+            subroutine(frame)
+        );
+    }
+}
+
+/// ANSI styles `show_memory` highlights bytes with, in order of priority.
+const PC_STYLE: &str = "\x1b[7m";
+const I_STYLE: &str = "\x1b[4;36m";
+const WRITTEN_STYLE: &str = "\x1b[1;33m";
+const FONT_STYLE: &str = "\x1b[2m";
+
+/// Prints `len` bytes of memory from `address` as hex and ASCII, 16 to a
+/// line. Unless stdout is redirected the program counter, I, `written` and
+/// the font are highlighted.
+fn show_memory(chip: &Chip8, address: u16, len: u16, written: &[bool]) {
+    let color = io::stdout().is_terminal();
+    let start = address as usize;
+    let end = (start + len as usize).min(chip.memory.len());
+    let style = |address: usize| {
+        if !color {
+            None
+        } else if address == chip.pc as usize || address == chip.pc as usize + 1 {
+            Some(PC_STYLE)
+        } else if address == chip.i as usize {
+            Some(I_STYLE)
+        } else if written[address] {
+            Some(WRITTEN_STYLE)
+        } else if address < 0x50 {
+            Some(FONT_STYLE)
+        } else {
+            None
+        }
+    };
+    for line in (start..end).step_by(16) {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        for address in line..(line + 16).min(end) {
+            let byte = chip.memory[address];
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            match style(address) {
+                Some(style) => {
+                    hex.push_str(&format!(" {}{:02X}\x1b[0m", style, byte));
+                    ascii.push_str(&format!("{}{}\x1b[0m", style, c));
+                }
+                None => {
+                    hex.push_str(&format!(" {:02X}", byte));
+                    ascii.push(c);
+                }
+            }
+        }
+        // Keep the ASCII column aligned on a short last line
+        let padding = 3 * (line + 16 - (line + 16).min(end));
+        println!("{:03X}:{}{}  {}", line, hex, " ".repeat(padding), ascii);
+    }
+    if color {
+        println!(
+            "{}PC\x1b[0m {}I\x1b[0m {}written\x1b[0m {}font\x1b[0m",
+            PC_STYLE, I_STYLE, WRITTEN_STYLE, FONT_STYLE
+        );
+    }
+}
+
+/// Sets the register named `register` to `value`, which has to fit.
+fn set_register(chip: &mut Chip8, register: &str, value: i64) -> Result<(), String> {
+    let name = register.to_lowercase();
+    let max = match name.as_str() {
+        "i" | "pc" => chip.memory.len() as i64 - 1,
+        _ => 0xFF,
+    };
+    if !(0..=max).contains(&value) {
+        return Err(format!("Value out of range for {}: {}", register, value));
+    }
+    match name.as_str() {
+        "i" => chip.i = value as u16,
+        "pc" => chip.pc = value as u16,
+        "dt" => chip.delay_timer = value as u8,
+        "st" => chip.sound_timer = value as u8,
+        _ => {
+            let index = name
+                .strip_prefix('v')
+                .filter(|index| index.len() == 1)
+                .and_then(|index| usize::from_str_radix(index, 16).ok())
+                .ok_or(format!("Unknown register: {}", register))?;
+            chip.v[index] = value as u8;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::symbols::Symbols;
+
+/// Returns the mnemonic for a single opcode, in the syntax used by Cowgod's
+/// CHIP-8 technical reference, with addresses that have a label in `symbols`
+/// replaced by the label. Opcodes that don't decode to an instruction are
+/// returned as a `DW` data word.
+pub fn disassemble(opcode: u16, symbols: &Symbols) -> String {
+    let target = |address: u16| match symbols.name(address) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:03X}", address),
+    };
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            _ => format!("SYS {}", target(nnn)),
+        },
+        0x1000 => format!("JP {}", target(nnn)),
+        0x2000 => format!("CALL {}", target(nnn)),
+        0x3000 => format!("SE V{:X}, 0x{:02X}", x, nn),
+        0x4000 => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        0x5000 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, 0x{:02X}", x, nn),
+        0x7000 => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        0x8000 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW 0x{:04X}", opcode),
+        },
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, {}", target(nnn)),
+        0xB000 => format!("JP V0, {}", target(nnn)),
+        0xC000 => format!("RND V{:X}, 0x{:02X}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW 0x{:04X}", opcode),
+        },
+        0xF000 => match nn {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => format!("DW 0x{:04X}", opcode),
+        },
+        _ => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+/// Disassembles `rom` as if loaded at `origin`, one line per two bytes:
+/// address, raw opcode and mnemonic, with a `label:` line before addresses
+/// that have one in `symbols`. The sweep is linear, so sprite data mixed
+/// with code shows up as (nonsensical) instructions.
+pub fn disassemble_rom(rom: &[u8], origin: u16, symbols: &Symbols) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (i, bytes) in rom.chunks(2).enumerate() {
+        let address = origin as usize + i * 2;
+        // A label at an odd address goes before the line it is in the middle of
+        for label in [address, address + 1] {
+            if let Some(name) = symbols.name(label as u16) {
+                lines.push(format!("{}:", name));
+            }
+        }
+        lines.push(match *bytes {
+            [hi, lo] => {
+                let opcode = (hi as u16) << 8 | lo as u16;
+                format!(
+                    "{:03X}: {:04X}  {}",
+                    address,
+                    opcode,
+                    disassemble(opcode, symbols)
+                )
+            }
+            _ => format!("{:03X}: {:02X}    DB 0x{:02X}", address, bytes[0], bytes[0]),
+        });
+    }
+    lines
+}
@@ -0,0 +1,111 @@
+use crate::config::EffectsConfig;
+
+/// Highest `resolution` the config accepts
+pub const MAX_RESOLUTION: usize = 16;
+
+/// CRT style post-processing done on the CPU. The effects work on a frame
+/// upscaled to `resolution` texels per CHIP-8 pixel, which is what gives
+/// scanlines and the pixel grid room to show.
+#[derive(Default)]
+pub struct Effects {
+    /// Texels per CHIP-8 pixel in each direction
+    pub resolution: usize,
+    /// How much every other texel row is darkened, 0.0 to 1.0
+    pub scanlines: f32,
+    /// How much the texels between CHIP-8 pixels are darkened, 0.0 to 1.0
+    pub grid: f32,
+    /// Strength of the glow around lit pixels, 0.0 to 1.0
+    pub bloom: f32,
+}
+
+impl Effects {
+    pub fn from_config(config: &EffectsConfig) -> Self {
+        Effects {
+            resolution: config.resolution,
+            scanlines: config.scanlines,
+            grid: config.grid,
+            bloom: config.bloom,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.scanlines > 0.0 || self.grid > 0.0 || self.bloom > 0.0
+    }
+
+    /// Returns the number of texels per CHIP-8 pixel frames need to have.
+    pub fn scale(&self) -> usize {
+        if self.enabled() {
+            self.resolution
+        } else {
+            1
+        }
+    }
+
+    /// Applies the effects to `frame`, a `width` x `height` RGB24 buffer.
+    pub fn apply(&self, frame: &mut [u8], width: usize, height: usize) {
+        let k = self.resolution;
+        let glow = if self.bloom > 0.0 {
+            Some(blur(frame, width, height, (k / 2).max(1)))
+        } else {
+            None
+        };
+
+        for y in 0..height {
+            let mut row_factor = 1.0;
+            if y % 2 == 1 {
+                row_factor *= 1.0 - self.scanlines;
+            }
+            if y % k == k - 1 {
+                row_factor *= 1.0 - self.grid;
+            }
+            for x in 0..width {
+                let mut factor = row_factor;
+                if x % k == k - 1 {
+                    factor *= 1.0 - self.grid;
+                }
+                let i = (y * width + x) * 3;
+                for c in i..i + 3 {
+                    let mut value = frame[c] as f32 * factor;
+                    // The glow is added after darkening so it bleeds into
+                    // the gaps like light from a real phosphor would
+                    if let Some(glow) = &glow {
+                        value += glow[c] as f32 * self.bloom;
+                    }
+                    frame[c] = value.min(255.0) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Box blurs an RGB24 buffer with the given radius, horizontally and then
+/// vertically, using running sums.
+fn blur(frame: &[u8], width: usize, height: usize, radius: usize) -> Vec<u8> {
+    let mut horizontal = vec![0; frame.len()];
+    for y in 0..height {
+        blur_line(frame, &mut horizontal, y * width * 3, 3, width, radius);
+    }
+    let mut blurred = vec![0; frame.len()];
+    for x in 0..width {
+        blur_line(&horizontal, &mut blurred, x * 3, width * 3, height, radius);
+    }
+    blurred
+}
+
+/// Blurs `len` RGB texels of `src` starting at `start`, `stride` bytes apart.
+fn blur_line(src: &[u8], dst: &mut [u8], start: usize, stride: usize, len: usize, radius: usize) {
+    let window = (radius * 2 + 1) as u32;
+    for c in 0..3 {
+        let at = |i: usize| src[start + i * stride + c] as u32;
+        let mut sum: u32 = (0..=radius.min(len - 1)).map(at).sum();
+        for i in 0..len {
+            dst[start + i * stride + c] = (sum / window) as u8;
+            if i + radius + 1 < len {
+                sum += at(i + radius + 1);
+            }
+            if i >= radius {
+                sum -= at(i - radius);
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use crate::chip8::Chip8;
+use crate::symbols::Symbols;
+use std::fmt;
+
+/// An expression over the machine state, such as `V3 == 0x10 && I > 0x300`,
+/// used for conditional breakpoints and the debugger's watch list.
+///
+/// Numbers are decimal or hex with "0x". The state is read through
+/// `V0`-`VF`, `I`, `PC`, `SP`, `DT` or `delay_timer`, `ST` or
+/// `sound_timer`, `key[n]` and `mem[addr]` (names are case insensitive), and
+/// labels from the symbol file stand for their address.
+/// Operators are those of C with the same precedence: `! ~ -` (unary),
+/// `* / %`, `+ -`, `<< >>`, `< <= > >=`, `== !=`, `&`, `^`, `|`, `&&`,
+/// `||`. Comparisons and logic give 1 or 0, and any value other than 0
+/// counts as true.
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+enum Node {
+    Number(i64),
+    Register(usize),
+    I,
+    Pc,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+    Key(Box<Node>),
+    Memory(Box<Node>),
+    Unary(char, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+/// Binary operators by precedence, loosest binding first.
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Expr {
+    pub fn parse(source: &str, symbols: &Symbols) -> Result<Expr, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            symbols,
+        };
+        let node = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected \"{}\" in expression", token));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            node,
+        })
+    }
+
+    pub fn eval(&self, chip: &Chip8) -> Result<i64, String> {
+        eval(&self.node, chip)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn eval(node: &Node, chip: &Chip8) -> Result<i64, String> {
+    Ok(match node {
+        Node::Number(n) => *n,
+        Node::Register(r) => chip.v[*r] as i64,
+        Node::I => chip.i as i64,
+        Node::Pc => chip.pc as i64,
+        Node::Sp => chip.sp as i64,
+        Node::DelayTimer => chip.delay_timer as i64,
+        Node::SoundTimer => chip.sound_timer as i64,
+        Node::Key(index) => {
+            let index = eval(index, chip)?;
+            match chip.key.get(index as usize) {
+                Some(&key) if index >= 0 => key as i64,
+                _ => return Err(format!("No key {}", index)),
+            }
+        }
+        Node::Memory(address) => {
+            let address = eval(address, chip)?;
+            match chip.memory.get(address as usize) {
+                Some(&byte) if address >= 0 => byte as i64,
+                _ => return Err(format!("Address out of range: {:X}", address)),
+            }
+        }
+        Node::Unary(op, operand) => {
+            let value = eval(operand, chip)?;
+            match op {
+                '!' => (value == 0) as i64,
+                '~' => !value,
+                _ => value.wrapping_neg(),
+            }
+        }
+        Node::Binary(op, left, right) => {
+            let left = eval(left, chip)?;
+            // && and || don't evaluate the right side when the left decides
+            match *op {
+                "&&" if left == 0 => return Ok(0),
+                "||" if left != 0 => return Ok(1),
+                _ => {}
+            }
+            let right = eval(right, chip)?;
+            match *op {
+                "&&" | "||" => (right != 0) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                _ if right == 0 => return Err("Division by zero".to_string()),
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right),
+            }
+        }
+    })
+}
+
+fn tokenize(source: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = source.chars().collect();
+    let mut position = 0;
+    while position < chars.len() {
+        let c = chars[position];
+        if c.is_whitespace() {
+            position += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = position;
+            while position < chars.len()
+                && (chars[position].is_ascii_alphanumeric() || chars[position] == '_')
+            {
+                position += 1;
+            }
+            tokens.push(chars[start..position].iter().collect());
+        } else {
+            let two: String = chars[position..(position + 2).min(chars.len())]
+                .iter()
+                .collect();
+            if ["||", "&&", "==", "!=", "<=", ">=", "<<", ">>"].contains(&two.as_str()) {
+                tokens.push(two);
+                position += 2;
+            } else if "|^&<>+-*/%!~()[]".contains(c) {
+                tokens.push(c.to_string());
+                position += 1;
+            } else {
+                return Err(format!("Unexpected \"{}\" in expression", c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("Unexpected end of expression")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected \"{}\", found \"{}\"", expected, token)),
+        }
+    }
+
+    /// Parses operators of `level` in PRECEDENCE and tighter, left to right.
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut node = self.binary(level + 1)?;
+        while let Some(op) = self
+            .peek()
+            .and_then(|token| PRECEDENCE[level].iter().find(|&&op| op == token))
+        {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            node = Node::Binary(op, Box::new(node), Box::new(right));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next()? {
+            "(" => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            op @ ("!" | "~" | "-") => Ok(Node::Unary(
+                op.chars().next().unwrap(),
+                Box::new(self.unary()?),
+            )),
+            token => self.operand(token),
+        }
+    }
+
+    fn operand(&mut self, token: &str) -> Result<Node, String> {
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(token).map(Node::Number);
+        }
+        let name = token.to_lowercase();
+        if let Some(register) = name.strip_prefix('v') {
+            if register.len() == 1 {
+                if let Ok(register) = usize::from_str_radix(register, 16) {
+                    return Ok(Node::Register(register));
+                }
+            }
+        }
+        Ok(match name.as_str() {
+            "i" => Node::I,
+            "pc" => Node::Pc,
+            "sp" => Node::Sp,
+            "dt" | "delay_timer" => Node::DelayTimer,
+            "st" | "sound_timer" => Node::SoundTimer,
+            "key" | "mem" => {
+                self.expect("[")?;
+                let index = Box::new(self.binary(0)?);
+                self.expect("]")?;
+                if name == "key" {
+                    Node::Key(index)
+                } else {
+                    Node::Memory(index)
+                }
+            }
+            _ => match self.symbols.address(token) {
+                Some(address) => Node::Number(address as i64),
+                None => return Err(format!("Unknown name in expression: {}", token)),
+            },
+        })
+    }
+}
+
+/// Parses a number the way expressions and the debugger read them: decimal
+/// unless it starts with "0x".
+pub fn parse_number(token: &str) -> Result<i64, String> {
+    let number = match token.strip_prefix("0x").or(token.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => token.parse(),
+    };
+    number.map_err(|_| format!("Invalid number: {}", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(source: &str, chip: &Chip8) -> Result<i64, String> {
+        let mut symbols = Symbols::default();
+        symbols.insert("draw", 0x2A4).unwrap();
+        Expr::parse(source, &symbols)?.eval(chip)
+    }
+
+    fn eval(source: &str) -> Result<i64, String> {
+        eval_with(source, &Chip8::new())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("6 & 3 == 3"), Ok(0));
+        assert_eq!(eval("1 | 2 ^ 3 & 1"), Ok(3));
+        assert_eq!(eval("0 || 1 && 0"), Ok(0));
+        assert_eq!(eval("-2 * -3"), Ok(6));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("64 / 4 / 2"), Ok(8));
+        assert_eq!(eval("17 % 5 % 2"), Ok(0));
+        assert_eq!(eval("1 < 2 < 3"), Ok(1));
+    }
+
+    #[test]
+    fn numbers_and_names() {
+        assert_eq!(eval("0x1F + 0XA + 10"), Ok(51));
+        assert_eq!(eval("draw"), Ok(0x2A4));
+        let mut chip = Chip8::new();
+        chip.v[0xA] = 7;
+        chip.i = 0x300;
+        chip.memory[0x301] = 0x42;
+        chip.key[3] = 1;
+        assert_eq!(eval_with("va + VA", &chip), Ok(14));
+        assert_eq!(eval_with("mem[I + 1]", &chip), Ok(0x42));
+        assert_eq!(eval_with("key[3] && pc == 0x200", &chip), Ok(1));
+    }
+
+    #[test]
+    fn short_circuit() {
+        assert_eq!(eval("0 && 1 / 0"), Ok(0));
+        assert_eq!(eval("1 || mem[0x10000]"), Ok(1));
+    }
+
+    #[test]
+    fn rejected() {
+        assert!(eval("").is_err());
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("1 2").is_err());
+        assert!(eval("0xZZ").is_err());
+        assert!(eval("V10").is_err());
+        assert!(eval("nowhere").is_err());
+        assert!(eval("key 3").is_err());
+        assert!(eval("1 $ 2").is_err());
+        assert_eq!(eval("1 / 0"), Err("Division by zero".to_string()));
+        assert!(eval("key[16]").is_err());
+        assert!(eval("mem[-1]").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::config::GamepadConfig;
+use crate::keymap::parse_key;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+use std::collections::{HashMap, HashSet};
+
+/// Stick values within +-deadzone count as centered, unless configured
+pub const DEADZONE: i16 = 8000;
+
+/// Maps game controller buttons and stick directions to CHIP-8 keys.
+///
+/// By default the D-pad and the left stick map to 2/4/6/8, which most games
+/// use for movement, and the face buttons to 5, A, 7 and 9.
+pub struct PadMap {
+    buttons: HashMap<Button, u8>,
+    /// Keyed by axis and direction, `true` being the positive direction
+    axes: HashMap<(Axis, bool), u8>,
+    deadzone: i16,
+}
+
+impl PadMap {
+    pub fn new() -> Self {
+        let buttons = [
+            (Button::DPadUp, 0x2),
+            (Button::DPadDown, 0x8),
+            (Button::DPadLeft, 0x4),
+            (Button::DPadRight, 0x6),
+            (Button::A, 0x5),
+            (Button::B, 0xA),
+            (Button::X, 0x7),
+            (Button::Y, 0x9),
+            (Button::Back, 0xC),
+            (Button::Start, 0xF),
+        ];
+        let axes = [
+            ((Axis::LeftY, false), 0x2),
+            ((Axis::LeftY, true), 0x8),
+            ((Axis::LeftX, false), 0x4),
+            ((Axis::LeftX, true), 0x6),
+        ];
+        PadMap {
+            buttons: buttons.iter().cloned().collect(),
+            axes: axes.iter().cloned().collect(),
+            deadzone: DEADZONE,
+        }
+    }
+
+    /// Builds the mapping from the defaults plus the overrides in `config`.
+    /// Buttons use SDL's mapping names ("a", "dpup", "leftshoulder", ...) and
+    /// stick directions the axis name with a sign appended ("leftx-").
+    pub fn from_config(config: &GamepadConfig) -> Result<Self, String> {
+        let mut map = PadMap::new();
+        map.deadzone = config.deadzone;
+        for (name, key) in config.buttons.iter() {
+            let button =
+                Button::from_string(name).ok_or_else(|| format!("Unknown button: {}", name))?;
+            map.buttons.insert(button, parse_key(key)?);
+        }
+        for (name, key) in config.axes.iter() {
+            let (axis, positive) = if let Some(axis) = name.strip_suffix('+') {
+                (axis, true)
+            } else if let Some(axis) = name.strip_suffix('-') {
+                (axis, false)
+            } else {
+                return Err(format!("Axis direction must end in + or -: {}", name));
+            };
+            let axis = Axis::from_string(axis).ok_or_else(|| format!("Unknown axis: {}", name))?;
+            map.axes.insert((axis, positive), parse_key(key)?);
+        }
+        Ok(map)
+    }
+}
+
+/// A controller input that can hold a CHIP-8 key
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Input {
+    Button(Button),
+    /// An axis and direction, `true` being the positive direction
+    Stick(Axis, bool),
+}
+
+/// Keeps track of the connected game controllers and turns their events into
+/// CHIP-8 key presses.
+pub struct Gamepads {
+    /// None if SDL couldn't start its game controller support
+    subsystem: Option<GameControllerSubsystem>,
+    /// Open controllers by joystick instance id
+    controllers: HashMap<u32, GameController>,
+    map: PadMap,
+    /// Inputs currently held, by joystick instance id
+    held: HashSet<(u32, Input)>,
+    /// Held inputs per CHIP-8 key, so a key stays down until the last
+    /// button or stick direction mapped to it is released
+    holds: [u8; 16],
+}
+
+impl Gamepads {
+    pub fn new(subsystem: Option<GameControllerSubsystem>, map: PadMap) -> Self {
+        Gamepads {
+            subsystem,
+            controllers: HashMap::new(),
+            map,
+            held: HashSet::new(),
+            holds: [0; 16],
+        }
+    }
+
+    /// Handles controller events and updates `keys` accordingly. Controllers
+    /// that are already plugged in at startup are reported by SDL as added
+    /// devices too, so hotplugging needs no special treatment.
+    pub fn handle_event(&mut self, event: &Event, keys: &mut [u8; 16]) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                let subsystem = match &self.subsystem {
+                    Some(subsystem) => subsystem,
+                    None => return,
+                };
+                match subsystem.open(which) {
+                    Ok(controller) => {
+                        println!("Controller connected: {}", controller.name());
+                        self.controllers
+                            .insert(controller.instance_id() as u32, controller);
+                    }
+                    Err(e) => println!("Could not open controller {}: {}", which, e),
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(controller) = self.controllers.remove(&which) {
+                    println!("Controller disconnected: {}", controller.name());
+                }
+                let inputs: Vec<Input> = self
+                    .held
+                    .iter()
+                    .filter(|&&(id, _)| id == which)
+                    .map(|&(_, input)| input)
+                    .collect();
+                for input in inputs {
+                    self.release(which, input, keys);
+                }
+            }
+            Event::ControllerButtonDown { which, button, .. } => {
+                self.press(which, Input::Button(button), keys)
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.release(which, Input::Button(button), keys)
+            }
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => {
+                for &positive in [false, true].iter() {
+                    let pushed = if positive {
+                        value > self.map.deadzone
+                    } else {
+                        value < -self.map.deadzone
+                    };
+                    // Only touch the key when the direction changes so that
+                    // stick noise doesn't release keys held on the keyboard
+                    let input = Input::Stick(axis, positive);
+                    if pushed {
+                        self.press(which, input, keys);
+                    } else {
+                        self.release(which, input, keys);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn key(&self, input: Input) -> Option<usize> {
+        let key = match input {
+            Input::Button(button) => self.map.buttons.get(&button),
+            Input::Stick(axis, positive) => self.map.axes.get(&(axis, positive)),
+        };
+        key.map(|&key| key as usize)
+    }
+
+    fn press(&mut self, which: u32, input: Input, keys: &mut [u8; 16]) {
+        if let Some(key) = self.key(input) {
+            if self.held.insert((which, input)) {
+                self.holds[key] += 1;
+                keys[key] = 1;
+            }
+        }
+    }
+
+    fn release(&mut self, which: u32, input: Input, keys: &mut [u8; 16]) {
+        if let Some(key) = self.key(input) {
+            if self.held.remove(&(which, input)) {
+                self.holds[key] -= 1;
+                if self.holds[key] == 0 {
+                    keys[key] = 0;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::chip8::{HEIGHT, WIDTH};
+use crate::palette::Palette;
+use std::fmt::Write;
+
+/// Terminal graphics protocols that can show the display as a bitmap.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    /// DEC Sixel, supported by xterm, mlterm, foot, WezTerm and others
+    Sixel,
+    /// The kitty graphics protocol, also supported by WezTerm and Konsole
+    Kitty,
+}
+
+impl Protocol {
+    /// Returns the protocol for `name`, or None for "none".
+    pub fn from_name(name: &str) -> Result<Option<Protocol>, String> {
+        match name {
+            "none" => Ok(None),
+            "sixel" => Ok(Some(Protocol::Sixel)),
+            "kitty" => Ok(Some(Protocol::Kitty)),
+            _ => Err(format!(
+                "Unknown graphics protocol: {} (expected none, sixel or kitty)",
+                name
+            )),
+        }
+    }
+
+    /// Appends the escape sequence showing `gfx` at the cursor position to
+    /// `output`, with every CHIP-8 pixel drawn as `scale` x `scale` pixels.
+    /// For kitty, that assumes 8x16 pixel character cells.
+    pub fn encode(self, output: &mut String, gfx: &[u8], palette: &Palette, scale: usize) {
+        match self {
+            Protocol::Sixel => sixel(output, gfx, palette, scale),
+            Protocol::Kitty => kitty(output, gfx, palette, scale),
+        }
+    }
+}
+
+/// Number of colours the sixel palette is set up with, one per pixel value
+const SIXEL_COLORS: u8 = 4;
+
+fn sixel(output: &mut String, gfx: &[u8], palette: &Palette, scale: usize) {
+    let (width, height) = (WIDTH * scale, HEIGHT * scale);
+    let pixel = |x: usize, y: usize| gfx[(y / scale) * WIDTH + x / scale];
+
+    // Pixel aspect ratio 1:1 and the image size
+    let _ = write!(output, "\x1bP0;1q\"1;1;{};{}", width, height);
+    // Colour components are given in percent
+    for index in 0..SIXEL_COLORS {
+        let color = palette.color(index);
+        let percent = |c: u8| c as u32 * 100 / 255;
+        let _ = write!(
+            output,
+            "#{};2;{};{};{}",
+            index,
+            percent(color.r),
+            percent(color.g),
+            percent(color.b)
+        );
+    }
+
+    // Each sixel character covers a column of 6 pixels. A band is written
+    // once per colour, returning to its start with "$" in between.
+    for band in (0..height).step_by(6) {
+        for index in 0..SIXEL_COLORS {
+            let column = |x: usize| {
+                (0..6)
+                    .filter(|row| band + row < height && pixel(x, band + row) == index)
+                    .fold(0, |bits, row| bits | 1 << row)
+            };
+            if (0..width).all(|x| column(x) == 0) {
+                continue;
+            }
+            let _ = write!(output, "#{}", index);
+            let mut x = 0;
+            while x < width {
+                let bits = column(x);
+                let mut run = 1;
+                while x + run < width && column(x + run) == bits {
+                    run += 1;
+                }
+                let c = (0x3F + bits) as u8 as char;
+                if run > 3 {
+                    let _ = write!(output, "!{}{}", run, c);
+                } else {
+                    // repeat_n needs Rust 1.82
+                    #[allow(clippy::manual_repeat_n)]
+                    output.extend(std::iter::repeat(c).take(run));
+                }
+                x += run;
+            }
+            output.push('$');
+        }
+        output.push('-');
+    }
+    output.push_str("\x1b\\");
+}
+
+/// Largest payload the kitty protocol accepts per escape sequence
+const KITTY_CHUNK: usize = 4096;
+/// Size of a character cell in pixels the kitty placement is sized for, as
+/// the terminal scales the image to whole cells
+const KITTY_CELL: (usize, usize) = (8, 16);
+
+fn kitty(output: &mut String, gfx: &[u8], palette: &Palette, scale: usize) {
+    let mut rgb = Vec::with_capacity(WIDTH * HEIGHT * 3);
+    for &pixel in gfx {
+        let color = palette.color(pixel);
+        rgb.extend_from_slice(&[color.r, color.g, color.b]);
+    }
+    let columns = (WIDTH * scale).div_ceil(KITTY_CELL.0);
+    let rows = (HEIGHT * scale).div_ceil(KITTY_CELL.1);
+
+    // Only the 64x32 display is sent, the terminal scales it up to the
+    // columns and rows. Reusing the image and placement ids replaces the
+    // previous frame instead of stacking images. C=1 keeps the cursor where
+    // it is.
+    let data = base64(&rgb);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        if i == 0 {
+            let _ = write!(
+                output,
+                "\x1b_Ga=T,f=24,s={},v={},c={},r={},i=1,p=1,q=2,C=1,m={};",
+                WIDTH, HEIGHT, columns, rows, more
+            );
+        } else {
+            let _ = write!(output, "\x1b_Gm={};", more);
+        }
+        // Base64 is plain ASCII
+        output.push_str(std::str::from_utf8(chunk).unwrap());
+        output.push_str("\x1b\\");
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bytes = [
+            group[0],
+            *group.get(1).unwrap_or(&0),
+            *group.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(ALPHABET[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,163 @@
+use crate::config::KeysConfig;
+use sdl2::keyboard::{Keycode, Scancode};
+use std::collections::HashMap;
+
+// The CHIP-8 keypad in the order its keys are laid out, row by row:
+// 1 2 3 C
+// 4 5 6 D
+// 7 8 9 E
+// A 0 B F
+const KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, //
+    0x4, 0x5, 0x6, 0xD, //
+    0x7, 0x8, 0x9, 0xE, //
+    0xA, 0x0, 0xB, 0xF, //
+];
+
+/// A host key, either by its physical position or by the character it produces.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Binding {
+    Scancode(Scancode),
+    Keycode(Keycode),
+}
+
+impl Binding {
+    /// Parses a binding name as used in the config file. Plain names are SDL
+    /// scancode names (the physical key on a US layout, e.g. "Q" or "Keypad 7"),
+    /// names prefixed with "key:" are SDL keycode names (e.g. "key:Y").
+    pub fn from_name(name: &str) -> Result<Binding, String> {
+        if let Some(name) = name.strip_prefix("key:") {
+            Keycode::from_name(name)
+                .map(Binding::Keycode)
+                .ok_or_else(|| format!("Unknown keycode: {}", name))
+        } else {
+            Scancode::from_name(name)
+                .map(Binding::Scancode)
+                .ok_or_else(|| format!("Unknown scancode: {}", name))
+        }
+    }
+}
+
+/// Keyboard layout presets for the default keypad mapping.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Layout {
+    /// Physical keys 1-4, Q-R, A-F and Z-V, independent of the host layout
+    Scancode,
+    Qwerty,
+    Qwertz,
+    Azerty,
+    /// Keypad digits map to themselves, A-F to the keys around them
+    Numpad,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Result<Layout, String> {
+        match name.to_lowercase().as_str() {
+            "scancode" => Ok(Layout::Scancode),
+            "qwerty" => Ok(Layout::Qwerty),
+            "qwertz" => Ok(Layout::Qwertz),
+            "azerty" => Ok(Layout::Azerty),
+            "numpad" => Ok(Layout::Numpad),
+            _ => Err(format!("Unknown key layout: {}", name)),
+        }
+    }
+
+    #[rustfmt::skip]
+    fn bindings(self) -> Vec<(Binding, u8)> {
+        use Binding::Keycode as K;
+        use Binding::Scancode as S;
+
+        let keys: [Binding; 16] = match self {
+            Layout::Scancode => [
+                S(Scancode::Num1), S(Scancode::Num2), S(Scancode::Num3), S(Scancode::Num4),
+                S(Scancode::Q), S(Scancode::W), S(Scancode::E), S(Scancode::R),
+                S(Scancode::A), S(Scancode::S), S(Scancode::D), S(Scancode::F),
+                S(Scancode::Z), S(Scancode::X), S(Scancode::C), S(Scancode::V),
+            ],
+            Layout::Qwerty => [
+                K(Keycode::Num1), K(Keycode::Num2), K(Keycode::Num3), K(Keycode::Num4),
+                K(Keycode::Q), K(Keycode::W), K(Keycode::E), K(Keycode::R),
+                K(Keycode::A), K(Keycode::S), K(Keycode::D), K(Keycode::F),
+                K(Keycode::Z), K(Keycode::X), K(Keycode::C), K(Keycode::V),
+            ],
+            Layout::Qwertz => [
+                K(Keycode::Num1), K(Keycode::Num2), K(Keycode::Num3), K(Keycode::Num4),
+                K(Keycode::Q), K(Keycode::W), K(Keycode::E), K(Keycode::R),
+                K(Keycode::A), K(Keycode::S), K(Keycode::D), K(Keycode::F),
+                K(Keycode::Y), K(Keycode::X), K(Keycode::C), K(Keycode::V),
+            ],
+            // The AZERTY number row only produces digits with shift held,
+            // so it is bound by position
+            Layout::Azerty => [
+                S(Scancode::Num1), S(Scancode::Num2), S(Scancode::Num3), S(Scancode::Num4),
+                K(Keycode::A), K(Keycode::Z), K(Keycode::E), K(Keycode::R),
+                K(Keycode::Q), K(Keycode::S), K(Keycode::D), K(Keycode::F),
+                K(Keycode::W), K(Keycode::X), K(Keycode::C), K(Keycode::V),
+            ],
+            Layout::Numpad => {
+                return vec![
+                    (S(Scancode::Kp0), 0x0),
+                    (S(Scancode::Kp1), 0x1),
+                    (S(Scancode::Kp2), 0x2),
+                    (S(Scancode::Kp3), 0x3),
+                    (S(Scancode::Kp4), 0x4),
+                    (S(Scancode::Kp5), 0x5),
+                    (S(Scancode::Kp6), 0x6),
+                    (S(Scancode::Kp7), 0x7),
+                    (S(Scancode::Kp8), 0x8),
+                    (S(Scancode::Kp9), 0x9),
+                    (S(Scancode::KpDivide), 0xA),
+                    (S(Scancode::KpMultiply), 0xB),
+                    (S(Scancode::KpMinus), 0xC),
+                    (S(Scancode::KpPlus), 0xD),
+                    (S(Scancode::KpEnter), 0xE),
+                    (S(Scancode::KpPeriod), 0xF),
+                ];
+            }
+        };
+        keys.iter().cloned().zip(KEYPAD.iter().cloned()).collect()
+    }
+}
+
+/// Maps host keys to CHIP-8 keys.
+pub struct KeyMap {
+    bindings: HashMap<Binding, u8>,
+}
+
+impl KeyMap {
+    pub fn new(layout: Layout) -> Self {
+        KeyMap {
+            bindings: layout.bindings().into_iter().collect(),
+        }
+    }
+
+    /// Builds the mapping from a layout preset plus the overrides in `config`.
+    pub fn from_config(config: &KeysConfig) -> Result<Self, String> {
+        let mut keymap = KeyMap::new(Layout::from_name(&config.layout)?);
+        for (name, key) in config.bindings.iter() {
+            keymap.bind(Binding::from_name(name)?, parse_key(key)?);
+        }
+        Ok(keymap)
+    }
+
+    pub fn bind(&mut self, binding: Binding, key: u8) {
+        self.bindings.insert(binding, key);
+    }
+
+    /// Returns the CHIP-8 key for a host key event. Keycode bindings win over
+    /// scancode bindings so that overrides of either kind behave predictably.
+    pub fn lookup(&self, keycode: Option<Keycode>, scancode: Option<Scancode>) -> Option<u8> {
+        keycode
+            .and_then(|k| self.bindings.get(&Binding::Keycode(k)))
+            .or_else(|| scancode.and_then(|s| self.bindings.get(&Binding::Scancode(s))))
+            .cloned()
+    }
+}
+
+/// Parses a single hex digit naming a CHIP-8 key.
+pub fn parse_key(key: &str) -> Result<u8, String> {
+    match u8::from_str_radix(key.trim_start_matches("0x"), 16) {
+        Ok(k) if k < 16 => Ok(k),
+        _ => Err(format!("Invalid CHIP-8 key: {}", key)),
+    }
+}
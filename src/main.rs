@@ -1,81 +1,272 @@
 extern crate sdl2;
 
+mod asm;
+mod audio;
 mod chip8;
+mod cli;
+mod compare;
+mod config;
+mod debugger;
+mod disasm;
+mod effects;
+mod expr;
+mod gamepad;
+mod graphics;
+mod keymap;
+mod osd;
+mod palette;
+mod profile;
+mod renderer;
+mod symbols;
+mod terminal;
+mod trace;
 
-use chip8::Chip8;
-use sdl2::audio::{AudioCallback, AudioSpecDesired};
-use sdl2::event::Event;
+use audio::Waveform;
+use chip8::{Chip8, OutOfBounds, Quirks, HEIGHT, WIDTH};
+use cli::{Args, Command};
+use config::Config;
+use debugger::Debugger;
+use effects::Effects;
+use gamepad::{Gamepads, PadMap};
+use keymap::KeyMap;
+use osd::Osd;
+use palette::Palette;
+use profile::Profile;
+use renderer::{Renderer, Status};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
-use sdl2::pixels;
-use sdl2::rect::Rect;
+use sdl2::video::FullscreenType;
 use std::env;
+use std::fs;
+use std::process;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use symbols::Symbols;
+use trace::Tracer;
 
-const SCALE_FACTOR: u32 = 20;
-const SCREEN_WIDTH: u32 = 64 * SCALE_FACTOR;
-const SCREEN_HEIGHT: u32 = 32 * SCALE_FACTOR;
+const FRAMES_PER_SECOND: u32 = 60;
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+/// Returns how many instructions to run in the 60 Hz frame `frame` to run
+/// `speed` instructions per second. When `speed` isn't a multiple of 60, the
+/// remainder is spread over the frames of each second, so 500 runs 8 or 9
+/// instructions a frame rather than 8 every frame, which would be 480. At
+/// least one instruction runs per frame.
+fn frame_cycles(speed: u32, frame: u64) -> u32 {
+    let speed = speed.max(FRAMES_PER_SECOND) as u64;
+    let frame = frame % FRAMES_PER_SECOND as u64;
+    let done = |frames: u64| speed * frames / FRAMES_PER_SECOND as u64;
+    (done(frame + 1) - done(frame)) as u32
 }
 
-impl AudioCallback for SquareWave {
-    type Channel = f32;
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Runs the command given on the command line.
+fn run() -> Result<(), String> {
+    let args = Args::parse(env::args().skip(1))?;
+    let mut config = Config::load(args.config.as_deref(), args.rom())?;
+    args.apply(&mut config);
+    config.check()?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let symbols = match args.rom() {
+        Some(rom) => Symbols::for_rom(rom, args.symbols.as_deref())?,
+        None => Symbols::default(),
+    };
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = self.volume * if self.phase < 0.5 { 1.0 } else { -1.0 };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+    match args.command {
+        Command::Run(rom) => match config.display.frontend.as_str() {
+            "sdl" => run_sdl(&rom, &config, args.debug, &symbols),
+            "terminal" if args.debug => Err("The debugger needs the sdl frontend".to_string()),
+            "terminal" => terminal::run(&rom, &config, &symbols),
+            frontend => Err(format!(
+                "Unknown frontend: {} (expected sdl or terminal)",
+                frontend
+            )),
+        },
+        Command::Disasm(rom) => {
+            for line in disasm::disassemble_rom(&read(&rom)?, 0x200, &symbols) {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Command::Asm { source, output } => {
+            let source = fs::read_to_string(&source).map_err(|e| format!("{}: {}", source, e))?;
+            let (rom, labels) = asm::assemble(&source)?;
+            fs::write(&output, &rom).map_err(|e| format!("{}: {}", output.display(), e))?;
+            println!("Wrote {} bytes to {}", rom.len(), output.display());
+            if !labels.is_empty() {
+                let path = output.with_extension("sym");
+                fs::write(&path, labels.to_text())
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                println!("Wrote symbols to {}", path.display());
+            }
+            Ok(())
+        }
+        Command::Info(rom) => {
+            let content = read(&rom)?;
+            println!("File:    {}", rom);
+            println!("Size:    {} bytes", content.len());
+            println!("Range:   0x200-0x{:03X}", 0x200 + content.len().max(1) - 1);
+            println!(
+                "Fits:    {}",
+                if content.len() <= chip8::MAX_ROM_SIZE {
+                    "yes"
+                } else {
+                    "no, ROM too big for memory"
+                }
+            );
+            Ok(())
+        }
+        Command::Test { rom, frames } => {
+            let mut chip = load(&rom, &config)?;
+            let mut tracer = Tracer::from_config(&config.trace, &symbols)?;
+            let speed = config.emulation.speed;
+            let mut result = Ok(());
+            for _ in 0..frames {
+                let cycles = frame_cycles(speed, chip.frame);
+                if let Err(fault) = trace::run_frame(&mut chip, cycles, &mut tracer) {
+                    result = Err(fault.to_string());
+                    break;
+                }
+            }
+            trace::finish(&chip, &mut tracer, &config, &symbols)?;
+            for row in chip.gfx.chunks(WIDTH) {
+                let line: String = row
+                    .iter()
+                    .map(|&p| if p != 0 { '#' } else { '.' })
+                    .collect();
+                println!("{}", line);
+            }
+            result
+        }
+        Command::Compare { rom, reference } => {
+            let mut chip = load(&rom, &config)?;
+            let reference = fs::read_to_string(&reference)
+                .map_err(|e| format!("{}: {}", reference.display(), e))?;
+            compare::compare(&mut chip, &reference, config.emulation.speed, &symbols)
+        }
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
         }
     }
 }
 
-fn main() -> Result<(), String> {
-    let args = env::args().collect::<Vec<String>>();
-    if args.len() < 2 {
-        return Err(format!("Usage: ./chipulator8 chip8application"));
-    }
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Creates a `Chip8` set up according to `config` with `rom` loaded.
+fn load(rom: &str, config: &Config) -> Result<Chip8, String> {
     let mut chip = Chip8::new();
-    chip.load_application(&args[1]);
+    chip.quirks = Quirks::from_preset(&config.emulation.quirks)?;
+    if let Some(display_wait) = config.emulation.display_wait {
+        chip.quirks.display_wait = display_wait;
+    }
+    chip.out_of_bounds = OutOfBounds::from_name(&config.emulation.out_of_bounds)?;
+    chip.set_stack_depth(match config.emulation.stack_depth {
+        // The stack pointer is 16 bits
+        Some(depth) if !(1..=0xFFFF).contains(&depth) => {
+            return Err(format!("Invalid stack depth: {} (expected 1-65535)", depth))
+        }
+        Some(depth) => depth,
+        None if config.emulation.quirks == "vip" => chip8::VIP_STACK_DEPTH,
+        None => chip8::STACK_DEPTH,
+    });
+    if let Some(seed) = config.emulation.seed {
+        chip.seed(seed);
+    }
+    if config.profile.report.is_some() || config.profile.heatmap.is_some() {
+        chip.profile = Some(Profile::new(chip.memory.len()));
+    }
+    chip.load_application(rom);
+    Ok(chip)
+}
 
-    let sdl_context = sdl2::init()?;
+/// Runs `rom` in an SDL window until it is closed, optionally under the
+/// debugger.
+fn run_sdl(rom: &str, config: &Config, debug: bool, symbols: &Symbols) -> Result<(), String> {
+    let keymap = KeyMap::from_config(&config.keys)?;
+    let padmap = PadMap::from_config(&config.gamepad)?;
+    let palette = Palette::from_config(&config.display)?;
+    let scale = config.display.scale;
 
-    let audio_subsystem = sdl_context.audio()?;
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1), // mono
-        samples: None,     // default sample size
+    let mut chip = load(rom, config)?;
+    let mut tracer = Tracer::from_config(&config.trace, symbols)?;
+    let mut debugger = if debug {
+        Some(Debugger::new(&chip, symbols))
+    } else {
+        None
     };
 
-    let audio_device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-        // Show obtained AudioSpec
-        println!("{:?}", spec);
+    let sdl_context = sdl2::init()?;
 
-        // initialize the audio callback
-        SquareWave {
-            phase_inc: 240.0 / spec.freq as f32,
-            phase: 0.0,
-            volume: 0.25,
+    let waveform = Waveform::from_name(&config.audio.waveform)?;
+    let mut audio_device = if config.audio.enabled {
+        match audio::open(&sdl_context, &config.audio, waveform) {
+            Ok(device) => Some(device),
+            Err(e) => {
+                eprintln!("Warning: no audio available, running without sound: {}", e);
+                None
+            }
         }
-    })?;
+    } else {
+        None
+    };
+    // Without sound the beeper is shown instead
+    let visual_beep = config.display.visual_beep || audio_device.is_none();
+
+    // Like audio, controllers are optional
+    let controller_subsystem = match sdl_context.game_controller() {
+        Ok(subsystem) => Some(subsystem),
+        Err(e) => {
+            eprintln!(
+                "Warning: no game controller support, running without gamepads: {}",
+                e
+            );
+            None
+        }
+    };
+    let mut gamepads = Gamepads::new(controller_subsystem, padmap);
 
     let video_subsystem = sdl_context.video()?;
-    let window = video_subsystem
-        .window("chipulator8", SCREEN_WIDTH, SCREEN_HEIGHT)
-        .position_centered()
-        .opengl()
-        .build()
-        .map_err(|e| e.to_string())?;
-
-    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-    canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.present();
+    let mut window =
+        video_subsystem.window("chipulator8", WIDTH as u32 * scale, HEIGHT as u32 * scale);
+    window.position_centered().resizable().opengl();
+    if config.display.fullscreen {
+        window.fullscreen_desktop();
+    }
+    let window = window.build().map_err(|e| e.to_string())?;
 
+    let mut canvas = window.into_canvas();
+    if config.display.vsync {
+        canvas = canvas.present_vsync();
+    }
+    let mut canvas = canvas.build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut renderer = Renderer::new(&texture_creator, WIDTH, HEIGHT, palette)?;
+    renderer.integer_scaling = config.display.integer_scaling;
+    renderer.persistence = config.display.persistence;
+    renderer.effects = Effects::from_config(&config.effects);
+    renderer.update(&chip.gfx, WIDTH, HEIGHT)?;
+    let mut osd = Osd::new(&config.emulation.quirks);
+    osd.show_stats = config.display.stats;
+    let mut redraw = true;
+    let mut paused = false;
+    let mut fast_forward = false;
+    // Frames to run while paused, set by frame advance
+    let mut advance = 0;
+
+    let frame_duration = Duration::from_secs(1) / FRAMES_PER_SECOND;
+    let mut next_frame = Instant::now();
+    let mut last_present = Instant::now();
     let mut event_pump = sdl_context.event_pump()?;
     'running: loop {
         for event in event_pump.poll_iter() {
@@ -86,84 +277,211 @@ fn main() -> Result<(), String> {
                     ..
                 } => break 'running,
                 Event::KeyDown {
-                    keycode: Some(keycode),
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => {
+                    osd.show_stats = !osd.show_stats;
+                    redraw = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off,
+                    };
+                    window.set_fullscreen(fullscreen)?;
+                    redraw = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => {
+                    if let Some(audio_device) = &mut audio_device {
+                        let mut beeper = audio_device.lock();
+                        beeper.muted = !beeper.muted;
+                        osd.message(if beeper.muted {
+                            "Sound muted"
+                        } else {
+                            "Sound on"
+                        });
+                        redraw = true;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Pause),
+                    ..
+                } => {
+                    paused = !paused;
+                    osd.message(if paused { "Paused" } else { "Resumed" });
+                    redraw = true;
+                    if let Some(audio_device) = &mut audio_device {
+                        // Resuming doesn't restart the tone, so cut it off here
+                        audio_device.lock().start(0);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } if paused => advance += 1,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
                     ..
-                } => match keycode {
-                    Keycode::Num1 => chip.key[0x1] = 1,
-                    Keycode::Num2 => chip.key[0x2] = 1,
-                    Keycode::Num3 => chip.key[0x3] = 1,
-                    Keycode::Num4 => chip.key[0xC] = 1,
-
-                    Keycode::Q => chip.key[0x4] = 1,
-                    Keycode::W => chip.key[0x5] = 1,
-                    Keycode::E => chip.key[0x6] = 1,
-                    Keycode::R => chip.key[0xD] = 1,
-
-                    Keycode::A => chip.key[0x7] = 1,
-                    Keycode::S => chip.key[0x8] = 1,
-                    Keycode::D => chip.key[0x9] = 1,
-                    Keycode::F => chip.key[0xE] = 1,
-
-                    Keycode::Y => chip.key[0xA] = 1,
-                    Keycode::X => chip.key[0x0] = 1,
-                    Keycode::C => chip.key[0xB] = 1,
-                    Keycode::V => chip.key[0xF] = 1,
-                    _ => {}
-                },
+                } => fast_forward = true,
                 Event::KeyUp {
-                    keycode: Some(keycode),
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => fast_forward = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => {
+                    if let Some(debugger) = &mut debugger {
+                        debugger.pause(&chip);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    osd.message("Reset");
+                    // The profile covers the whole session
+                    let profile = chip.profile.take();
+                    chip = load(rom, config)?;
+                    chip.profile = profile;
+                    if let Some(audio_device) = &mut audio_device {
+                        audio_device.lock().start(0);
+                    }
+                    renderer.update(&chip.gfx, WIDTH, HEIGHT)?;
+                    redraw = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
                     ..
-                } => match keycode {
-                    Keycode::Num1 => chip.key[0x1] = 0,
-                    Keycode::Num2 => chip.key[0x2] = 0,
-                    Keycode::Num3 => chip.key[0x3] = 0,
-                    Keycode::Num4 => chip.key[0xC] = 0,
-
-                    Keycode::Q => chip.key[0x4] = 0,
-                    Keycode::W => chip.key[0x5] = 0,
-                    Keycode::E => chip.key[0x6] = 0,
-                    Keycode::R => chip.key[0xD] = 0,
-
-                    Keycode::A => chip.key[0x7] = 0,
-                    Keycode::S => chip.key[0x8] = 0,
-                    Keycode::D => chip.key[0x9] = 0,
-                    Keycode::F => chip.key[0xE] = 0,
-
-                    Keycode::Y => chip.key[0xA] = 0,
-                    Keycode::X => chip.key[0x0] = 0,
-                    Keycode::C => chip.key[0xB] = 0,
-                    Keycode::V => chip.key[0xF] = 0,
-                    _ => {}
-                },
-                _ => {}
+                } => {
+                    renderer.palette = renderer.palette.next();
+                    osd.message(format!("Palette: {}", renderer.palette.name));
+                    renderer.update(&chip.gfx, WIDTH, HEIGHT)?;
+                    redraw = true;
+                }
+                Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                }
+                | Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => redraw = true,
+                Event::KeyDown {
+                    keycode, scancode, ..
+                } => {
+                    if let Some(key) = keymap.lookup(keycode, scancode) {
+                        chip.key[key as usize] = 1;
+                    }
+                }
+                Event::KeyUp {
+                    keycode, scancode, ..
+                } => {
+                    if let Some(key) = keymap.lookup(keycode, scancode) {
+                        chip.key[key as usize] = 0;
+                    }
+                }
+                event => gamepads.handle_event(&event, &mut chip.key),
             }
         }
-        chip.emulate_cycle();
-        if chip.draw_flag {
-            canvas.clear();
-            for y in 0..32 {
-                for x in 0..64 {
-                    let mut color = pixels::Color::RGB(0, 0, 0);
-                    if chip.gfx[((y * 64) + x) as usize] != 0 {
-                        color = pixels::Color::RGB(255, 255, 255);
+        if let Some(debugger) = &mut debugger {
+            if !debugger.poll(&mut chip) {
+                break 'running;
+            }
+        }
+        let frames = if paused {
+            advance
+        } else if fast_forward {
+            config.emulation.fast_forward.max(1)
+        } else {
+            1
+        };
+        advance = 0;
+        let mut draw = false;
+        let mut executed = 0;
+        for _ in 0..frames {
+            let cycles = frame_cycles(config.emulation.speed, chip.frame);
+            let result = match &mut debugger {
+                Some(debugger) => debugger.run_frame(&mut chip, cycles, &mut tracer),
+                None => trace::run_frame(&mut chip, cycles, &mut tracer),
+            };
+            match result {
+                Ok(frame) => {
+                    draw |= frame.draw;
+                    executed += frame.cycles;
+                    if let (Some(sound), Some(audio_device)) = (frame.sound, &mut audio_device) {
+                        audio_device.lock().start(sound);
                     }
-                    canvas.set_draw_color(color);
-
-                    let x = x * SCALE_FACTOR;
-                    let y = y * SCALE_FACTOR;
-                    canvas.fill_rect(Rect::new(x as i32, y as i32, SCALE_FACTOR, SCALE_FACTOR))?;
+                }
+                Err(fault) => {
+                    eprintln!("{}", fault);
+                    osd.message(fault.to_string());
+                    // The debugger stops by itself, to let the fault be inspected
+                    paused |= debugger.is_none();
+                    draw = true;
+                    break;
                 }
             }
         }
-        canvas.present();
+        if osd.update(frames, executed) {
+            redraw = true;
+        }
 
-        if chip.sound_timer > 0 {
-            audio_device.resume();
+        let status = if paused || debugger.as_ref().is_some_and(|d| d.is_paused()) {
+            Status::Paused
+        } else if fast_forward {
+            Status::FastForward
         } else {
-            audio_device.pause();
+            Status::Running
+        };
+        if status != renderer.status {
+            renderer.status = status;
+            redraw = true;
+        }
+        let beep = visual_beep && chip.sound_timer > 0;
+        if beep != renderer.beep {
+            renderer.beep = beep;
+            redraw = true;
+        }
+        if draw || renderer.is_fading() {
+            renderer.update(&chip.gfx, WIDTH, HEIGHT)?;
+            redraw = true;
+        }
+        // With vsync, presenting waits for the monitor, so fast forwarding
+        // without a limit only presents one frame per 60 Hz frame
+        let unlimited = fast_forward && config.emulation.fast_forward == 0;
+        let wait = config.display.vsync && unlimited && last_present.elapsed() < frame_duration;
+        if redraw && !wait {
+            last_present = Instant::now();
+            renderer.draw(&mut canvas)?;
+            let viewport = renderer.viewport(canvas.output_size()?);
+            osd.draw(&mut canvas, viewport, &renderer.palette)?;
+            canvas.present();
+            redraw = false;
         }
 
-        thread::sleep(Duration::from_millis(2));
+        // Sleep until the next frame is due, unless we are already late or
+        // fast forwarding without a limit
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if unlimited {
+            next_frame = now;
+        } else if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
     }
-    Ok(())
+    trace::finish(&chip, &mut tracer, config, symbols)
 }
@@ -0,0 +1,205 @@
+use crate::chip8::FONTSET;
+use crate::palette::Palette;
+use sdl2::rect::Rect;
+use sdl2::render::WindowCanvas;
+use std::time::{Duration, Instant};
+
+/// The characters in `FONT`, in order. Hex digits come from the CHIP-8 font,
+/// lower case letters are drawn in upper case and anything else as "?".
+const CHARS: &str = " GHIJKLMNOPQRSTUVWXYZ.,:-+=/%()!?'_#";
+
+/// 4x5 pixel glyphs laid out like the CHIP-8 font: one byte per row with the
+/// leftmost pixel in the highest bit.
+const FONT: [u8; 180] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, //space
+    0xF0, 0x80, 0xB0, 0x90, 0xF0, //G
+    0x90, 0x90, 0xF0, 0x90, 0x90, //H
+    0x70, 0x20, 0x20, 0x20, 0x70, //I
+    0x10, 0x10, 0x10, 0x90, 0x60, //J
+    0x90, 0xA0, 0xC0, 0xA0, 0x90, //K
+    0x80, 0x80, 0x80, 0x80, 0xF0, //L
+    0x90, 0xF0, 0xF0, 0x90, 0x90, //M
+    0x90, 0xD0, 0xB0, 0x90, 0x90, //N
+    0x60, 0x90, 0x90, 0x90, 0x60, //O
+    0xE0, 0x90, 0xE0, 0x80, 0x80, //P
+    0x60, 0x90, 0x90, 0xA0, 0x50, //Q
+    0xE0, 0x90, 0xE0, 0xA0, 0x90, //R
+    0x70, 0x80, 0x60, 0x10, 0xE0, //S
+    0xF0, 0x40, 0x40, 0x40, 0x40, //T
+    0x90, 0x90, 0x90, 0x90, 0x60, //U
+    0x90, 0x90, 0x90, 0x60, 0x60, //V
+    0x90, 0x90, 0xF0, 0xF0, 0x90, //W
+    0x90, 0x60, 0x60, 0x60, 0x90, //X
+    0x90, 0x90, 0x60, 0x40, 0x40, //Y
+    0xF0, 0x20, 0x40, 0x80, 0xF0, //Z
+    0x00, 0x00, 0x00, 0x00, 0x40, //.
+    0x00, 0x00, 0x00, 0x40, 0x80, //,
+    0x00, 0x40, 0x00, 0x40, 0x00, //:
+    0x00, 0x00, 0xF0, 0x00, 0x00, //-
+    0x00, 0x40, 0xE0, 0x40, 0x00, //+
+    0x00, 0xF0, 0x00, 0xF0, 0x00, //=
+    0x10, 0x20, 0x40, 0x80, 0x00, //slash
+    0x90, 0x20, 0x40, 0x90, 0x00, //%
+    0x20, 0x40, 0x40, 0x40, 0x20, //(
+    0x40, 0x20, 0x20, 0x20, 0x40, //)
+    0x40, 0x40, 0x40, 0x00, 0x40, //bang
+    0xE0, 0x10, 0x60, 0x00, 0x40, //?
+    0x40, 0x40, 0x00, 0x00, 0x00, //'
+    0x00, 0x00, 0x00, 0x00, 0xF0, //_
+    0x50, 0xF0, 0x50, 0xF0, 0x50, //#
+];
+
+/// How long a message stays on screen
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+/// On-screen display drawn over the CHIP-8 picture, with a transient message
+/// in the bottom left corner and optional stats in the top right corner.
+pub struct Osd {
+    message: Option<(String, Instant)>,
+    pub show_stats: bool,
+    /// Quirks preset shown in the stats
+    quirks: String,
+    stats: Vec<String>,
+    /// Frames and instructions counted since `since`
+    frames: u32,
+    instructions: u32,
+    since: Instant,
+}
+
+impl Osd {
+    pub fn new(quirks: &str) -> Self {
+        Osd {
+            message: None,
+            show_stats: false,
+            quirks: quirks.to_string(),
+            stats: Vec::new(),
+            frames: 0,
+            instructions: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Shows `text` for a few seconds, replacing the current message.
+    pub fn message<S: Into<String>>(&mut self, text: S) {
+        self.message = Some((text.into(), Instant::now()));
+    }
+
+    /// Counts `frames` emulated frames in which `instructions` were executed,
+    /// updates the stats once per second and expires the message. Returns
+    /// whether the display needs to be redrawn.
+    pub fn update(&mut self, frames: u32, instructions: u32) -> bool {
+        let now = Instant::now();
+        let mut changed = false;
+        if let Some((_, shown)) = &self.message {
+            if now - *shown >= MESSAGE_DURATION {
+                self.message = None;
+                changed = true;
+            }
+        }
+
+        self.frames += frames;
+        self.instructions += instructions;
+        let elapsed = now - self.since;
+        if elapsed >= Duration::from_secs(1) {
+            let per_second = |count: u32| (count as f64 / elapsed.as_secs_f64()).round();
+            self.stats = vec![
+                format!("FPS {}", per_second(self.frames)),
+                format!("IPS {}", per_second(self.instructions)),
+                format!("QUIRKS {}", self.quirks),
+            ];
+            self.frames = 0;
+            self.instructions = 0;
+            self.since = now;
+            changed |= self.show_stats;
+        }
+        changed
+    }
+
+    /// Draws the display over `viewport`, the area the CHIP-8 picture covers.
+    pub fn draw(
+        &self,
+        canvas: &mut WindowCanvas,
+        viewport: Rect,
+        palette: &Palette,
+    ) -> Result<(), String> {
+        // Four font pixels per CHIP-8 pixel
+        let size = (viewport.height() / 128).max(1) as i32;
+        if self.show_stats && !self.stats.is_empty() {
+            let width = self
+                .stats
+                .iter()
+                .map(|line| text_width(line))
+                .max()
+                .unwrap();
+            let x = viewport.right() - (width + 3) * size;
+            draw_text(canvas, &self.stats, x, viewport.y() + size, size, palette)?;
+        }
+        if let Some((text, _)) = &self.message {
+            let y = viewport.bottom() - 8 * size;
+            draw_text(canvas, &[text], viewport.x() + size, y, size, palette)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the width of `text` in font pixels.
+fn text_width(text: &str) -> i32 {
+    (text.chars().count() * 5).max(1) as i32 - 1
+}
+
+fn glyph(c: char) -> &'static [u8] {
+    if let Some(digit) = c.to_digit(16) {
+        let digit = digit as usize;
+        return &FONTSET[digit * 5..digit * 5 + 5];
+    }
+    let index = CHARS
+        .find(c.to_ascii_uppercase())
+        .or_else(|| CHARS.find('?'))
+        .unwrap();
+    &FONT[index * 5..index * 5 + 5]
+}
+
+/// Draws `lines` with their top left corner at `x`, `y` on a box in the
+/// background colour, with each font pixel `size` window pixels big.
+fn draw_text<S: AsRef<str>>(
+    canvas: &mut WindowCanvas,
+    lines: &[S],
+    x: i32,
+    y: i32,
+    size: i32,
+    palette: &Palette,
+) -> Result<(), String> {
+    let width = lines
+        .iter()
+        .map(|line| text_width(line.as_ref()))
+        .max()
+        .unwrap_or(0);
+    let height = lines.len() as i32 * 6 - 1;
+    canvas.set_draw_color(palette.background());
+    canvas.fill_rect(Rect::new(
+        x,
+        y,
+        ((width + 2) * size) as u32,
+        ((height + 2) * size) as u32,
+    ))?;
+
+    let mut rects = Vec::new();
+    for (line_index, line) in lines.iter().enumerate() {
+        for (column, c) in line.as_ref().chars().enumerate() {
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for bit in 0..4 {
+                    if bits & (0x80 >> bit) != 0 {
+                        rects.push(Rect::new(
+                            x + (1 + column as i32 * 5 + bit) * size,
+                            y + (1 + line_index as i32 * 6 + row as i32) * size,
+                            size as u32,
+                            size as u32,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    canvas.set_draw_color(palette.color(1));
+    canvas.fill_rects(&rects)
+}
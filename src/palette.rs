@@ -0,0 +1,100 @@
+use crate::config::{parse_color, DisplayConfig};
+use sdl2::pixels::Color;
+
+/// Built-in palettes. The first colour is used for unset pixels, the second
+/// for set pixels and the rest for additional bitplanes.
+pub const PRESETS: [(&str, [u32; 4]); 6] = [
+    ("classic", [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]),
+    ("amber", [0x140C00, 0xFFB000, 0xB07A00, 0x5C4000]),
+    ("green", [0x001400, 0x33FF66, 0x22AA44, 0x115522]),
+    ("lcd", [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F]),
+    ("high-contrast", [0x000000, 0xFFFF00, 0x00FFFF, 0xFF00FF]),
+    // Okabe-Ito colours, distinguishable with all common forms of colour blindness
+    ("colorblind", [0x000000, 0xE69F00, 0x56B4E9, 0x009E73]),
+];
+
+#[derive(Clone, Debug)]
+pub struct Palette {
+    pub name: String,
+    colors: Vec<Color>,
+}
+
+impl Palette {
+    /// Returns the palette `config` names, with its `foreground` and
+    /// `background` colours replaced when those are set.
+    pub fn from_config(config: &DisplayConfig) -> Result<Palette, String> {
+        let mut palette = Palette::from_name(&config.palette)?;
+        if let Some(foreground) = &config.foreground {
+            let (r, g, b) = parse_color(foreground)?;
+            palette.colors[1] = Color::RGB(r, g, b);
+        }
+        if let Some(background) = &config.background {
+            let (r, g, b) = parse_color(background)?;
+            palette.colors[0] = Color::RGB(r, g, b);
+        }
+        Ok(palette)
+    }
+
+    /// Looks up a preset by name, or parses a custom palette given as a comma
+    /// separated list of at least two "#RRGGBB" colours: set pixels, unset
+    /// pixels and then the additional bitplanes.
+    pub fn from_name(name: &str) -> Result<Palette, String> {
+        if let Some((_, colors)) = PRESETS.iter().find(|(preset, _)| *preset == name) {
+            return Ok(Palette {
+                name: name.to_string(),
+                colors: colors
+                    .iter()
+                    .map(|&rgb| Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+                    .collect(),
+            });
+        }
+
+        let mut colors = name
+            .split(',')
+            .map(|color| parse_color(color.trim()).map(|(r, g, b)| Color::RGB(r, g, b)))
+            .collect::<Result<Vec<Color>, String>>()
+            .map_err(|_| {
+                format!(
+                    "Unknown palette: {} (expected one of {} or a list of #RRGGBB colours)",
+                    name,
+                    preset_names().join(", ")
+                )
+            })?;
+        if colors.len() < 2 {
+            return Err(format!("A palette needs at least two colours: {}", name));
+        }
+        // Like the old "<foreground>,<background>", set pixels come first
+        colors.swap(0, 1);
+        Ok(Palette {
+            name: name.to_string(),
+            colors,
+        })
+    }
+
+    /// Returns the colour for a pixel value. Palettes with fewer colours than
+    /// bitplanes reuse the foreground for the extra planes.
+    pub fn color(&self, pixel: u8) -> Color {
+        match self.colors.get(pixel as usize) {
+            Some(&color) => color,
+            None => self.colors[1],
+        }
+    }
+
+    pub fn background(&self) -> Color {
+        self.colors[0]
+    }
+
+    /// Returns the preset following this palette, wrapping around. A custom
+    /// palette is followed by the first preset.
+    pub fn next(&self) -> Palette {
+        let index = PRESETS
+            .iter()
+            .position(|(preset, _)| *preset == self.name)
+            .map_or(0, |i| (i + 1) % PRESETS.len());
+        Palette::from_name(PRESETS[index].0).unwrap()
+    }
+}
+
+fn preset_names() -> Vec<&'static str> {
+    PRESETS.iter().map(|(name, _)| *name).collect()
+}
@@ -0,0 +1,311 @@
+use crate::chip8::Chip8;
+use crate::config::ProfileConfig;
+use crate::disasm::disassemble;
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// Start of the program, the outermost "subroutine"
+const ENTRY: u16 = 0x200;
+/// Memory bytes per heatmap row and pixels per byte
+const HEATMAP_WIDTH: usize = 64;
+const HEATMAP_SCALE: usize = 4;
+
+/// Execution counts collected by `Chip8::emulate_cycle` while profiling.
+/// Instructions waiting for a key count every time they are retried, as
+/// that is where the time goes. A DXYN waiting for the vertical blank only
+/// counts once it draws.
+pub struct Profile {
+    /// Instructions executed, including retries
+    pub total: u64,
+    /// Executions by address
+    hits: Vec<u64>,
+    /// Executions by opcode, summed up by instruction kind for the report
+    opcodes: Vec<u64>,
+    /// Instructions executed in each subroutine itself and in everything it
+    /// called, by the address of the subroutine
+    subroutines: Vec<(u64, u64)>,
+    /// The instruction count when each subroutine's total was last counted
+    counted: Vec<u64>,
+}
+
+impl Profile {
+    pub fn new(memory_size: usize) -> Self {
+        Profile {
+            total: 0,
+            hits: vec![0; memory_size],
+            opcodes: vec![0; 0x10000],
+            subroutines: vec![(0, 0); 0x1000],
+            counted: vec![0; 0x1000],
+        }
+    }
+
+    /// Counts the execution of `opcode` at `pc` with the return addresses
+    /// `stack`, innermost last, read from `memory`.
+    pub fn record(&mut self, pc: u16, opcode: u16, stack: &[u16], memory: &[u8]) {
+        self.total += 1;
+        let size = self.hits.len();
+        self.hits[pc as usize % size] += 1;
+        self.opcodes[opcode as usize] += 1;
+
+        // Each return address points at the call, which holds the address
+        // of the subroutine it entered
+        let called = |&call: &u16| {
+            let byte = |address: u16| memory[address as usize % memory.len()] as u16;
+            (byte(call) << 8 | byte(call.wrapping_add(1))) & 0x0FFF
+        };
+        let current = stack.last().map_or(ENTRY, called);
+        self.subroutines[current as usize].0 += 1;
+        for subroutine in std::iter::once(ENTRY).chain(stack.iter().map(called)) {
+            // Recursive subroutines only count once towards their total
+            let subroutine = subroutine as usize;
+            if self.counted[subroutine] != self.total {
+                self.counted[subroutine] = self.total;
+                self.subroutines[subroutine].1 += 1;
+            }
+        }
+    }
+
+    /// Writes the report and heatmap `config` asks for.
+    pub fn write(
+        &self,
+        chip: &Chip8,
+        config: &ProfileConfig,
+        symbols: &Symbols,
+    ) -> Result<(), String> {
+        if let Some(path) = &config.report {
+            fs::write(path, self.report(chip, config.top, symbols))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        if let Some(path) = &config.heatmap {
+            self.write_heatmap(path)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the `top` most executed addresses, instruction kinds and
+    /// subroutines.
+    fn report(&self, chip: &Chip8, top: usize, symbols: &Symbols) -> String {
+        let mut report = format!("{} instructions executed\n", self.total);
+        let percent = |count: u64| count as f64 * 100.0 / self.total.max(1) as f64;
+
+        let mut addresses: Vec<(usize, u64)> = self
+            .hits
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        addresses.sort_by_key(|&(address, count)| (std::cmp::Reverse(count), address));
+        report.push_str("\nAddresses\n     count       %  address\n");
+        for &(address, count) in addresses.iter().take(top) {
+            let address = address as u16;
+            let byte = |address: u16| chip.memory[address as usize % chip.memory.len()] as u16;
+            let opcode = byte(address) << 8 | byte(address.wrapping_add(1));
+            let _ = writeln!(
+                report,
+                "{:>10} {:>6.2}%  {}  {:04X}  {}",
+                count,
+                percent(count),
+                symbols.format(address),
+                opcode,
+                disassemble(opcode, symbols)
+            );
+        }
+
+        let mut kinds: HashMap<&str, u64> = HashMap::new();
+        for (opcode, &count) in self.opcodes.iter().enumerate() {
+            if count > 0 {
+                *kinds.entry(kind(opcode as u16)).or_insert(0) += count;
+            }
+        }
+        let mut kinds: Vec<(&str, u64)> = kinds.into_iter().collect();
+        kinds.sort_by_key(|&(kind, count)| (std::cmp::Reverse(count), kind));
+        report.push_str("\nInstructions\n     count       %  kind\n");
+        for &(kind, count) in kinds.iter().take(top) {
+            let _ = writeln!(report, "{:>10} {:>6.2}%  {}", count, percent(count), kind);
+        }
+
+        let mut subroutines: Vec<(u16, (u64, u64))> = self
+            .subroutines
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(_, total))| total > 0)
+            .map(|(address, &counts)| (address as u16, counts))
+            .collect();
+        subroutines.sort_by_key(|&(address, (own, _))| (std::cmp::Reverse(own), address));
+        report.push_str("\nSubroutines\n      self       %       total       %  subroutine\n");
+        for &(address, (own, total)) in subroutines.iter().take(top) {
+            let _ = writeln!(
+                report,
+                "{:>10} {:>6.2}%  {:>10} {:>6.2}%  {}",
+                own,
+                percent(own),
+                total,
+                percent(total),
+                symbols.format(address)
+            );
+        }
+        report
+    }
+
+    /// Writes an image of memory as a binary PPM, 64 bytes to a row, with
+    /// bytes coloured from black over red and yellow to white by how often
+    /// they were executed, on a log scale.
+    fn write_heatmap(&self, path: &Path) -> Result<(), String> {
+        let width = HEATMAP_WIDTH * HEATMAP_SCALE;
+        let height = self.hits.len().div_ceil(HEATMAP_WIDTH) * HEATMAP_SCALE;
+        let max = (*self.hits.iter().max().unwrap_or(&0) as f64).ln_1p();
+        let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for y in 0..height {
+            for x in 0..width {
+                let address = (y / HEATMAP_SCALE) * HEATMAP_WIDTH + x / HEATMAP_SCALE;
+                let count = self.hits.get(address).copied().unwrap_or(0);
+                let heat = if max > 0.0 {
+                    (count as f64).ln_1p() / max
+                } else {
+                    0.0
+                };
+                image.extend_from_slice(&heat_color(heat));
+            }
+        }
+        fs::write(path, image).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Maps 0.0-1.0 to black, red, yellow and white in turn.
+fn heat_color(heat: f64) -> [u8; 3] {
+    let channel = |from: f64| ((heat * 3.0 - from).clamp(0.0, 1.0) * 255.0) as u8;
+    [channel(0.0), channel(1.0), channel(2.0)]
+}
+
+/// Returns the instruction pattern `opcode` matches, like "8XY4".
+fn kind(opcode: u16) -> &'static str {
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "00E0",
+            0x00EE => "00EE",
+            _ => "0NNN",
+        },
+        0x1000 => "1NNN",
+        0x2000 => "2NNN",
+        0x3000 => "3XNN",
+        0x4000 => "4XNN",
+        0x5000 => "5XY0",
+        0x6000 => "6XNN",
+        0x7000 => "7XNN",
+        0x8000 => match opcode & 0x000F {
+            0x0 => "8XY0",
+            0x1 => "8XY1",
+            0x2 => "8XY2",
+            0x3 => "8XY3",
+            0x4 => "8XY4",
+            0x5 => "8XY5",
+            0x6 => "8XY6",
+            0x7 => "8XY7",
+            0xE => "8XYE",
+            _ => "8XY?",
+        },
+        0x9000 => "9XY0",
+        0xA000 => "ANNN",
+        0xB000 => "BNNN",
+        0xC000 => "CXNN",
+        0xD000 => "DXYN",
+        0xE000 => match opcode & 0x00FF {
+            0x9E => "EX9E",
+            0xA1 => "EXA1",
+            _ => "EX??",
+        },
+        _ => match opcode & 0x00FF {
+            0x07 => "FX07",
+            0x0A => "FX0A",
+            0x15 => "FX15",
+            0x18 => "FX18",
+            0x1E => "FX1E",
+            0x29 => "FX29",
+            0x33 => "FX33",
+            0x55 => "FX55",
+            0x65 => "FX65",
+            _ => "FX??",
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_and_total() {
+        let mut memory = [0; 4096];
+        // 200: CALL 300, 302: CALL 300
+        memory[0x200..0x202].copy_from_slice(&[0x23, 0x00]);
+        memory[0x302..0x304].copy_from_slice(&[0x23, 0x00]);
+        let mut profile = Profile::new(memory.len());
+        profile.record(0x200, 0x2300, &[], &memory);
+        profile.record(0x300, 0x00E0, &[0x200], &memory);
+        assert_eq!(profile.subroutines[0x200], (1, 2));
+        assert_eq!(profile.subroutines[0x300], (1, 1));
+
+        // Called from itself, the subroutine's total only grows once
+        profile.record(0x300, 0x00E0, &[0x200, 0x302], &memory);
+        assert_eq!(profile.subroutines[0x200], (1, 3));
+        assert_eq!(profile.subroutines[0x300], (2, 2));
+        assert_eq!(profile.total, 3);
+        assert_eq!(profile.hits[0x300], 2);
+        assert_eq!(profile.opcodes[0x00E0], 2);
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(kind(0x00E0), "00E0");
+        assert_eq!(kind(0x00EE), "00EE");
+        assert_eq!(kind(0x0123), "0NNN");
+        assert_eq!(kind(0x8124), "8XY4");
+        assert_eq!(kind(0x812F), "8XY?");
+        assert_eq!(kind(0xD125), "DXYN");
+        assert_eq!(kind(0xE19E), "EX9E");
+        assert_eq!(kind(0xE1FF), "EX??");
+        assert_eq!(kind(0xF133), "FX33");
+        assert_eq!(kind(0xF1FF), "FX??");
+    }
+
+    #[test]
+    fn report_order() {
+        let mut chip = Chip8::new();
+        // 200: CLS, 202: CALL 300, 204: CLS, 300: RET
+        chip.memory[0x200..0x206].copy_from_slice(&[0x00, 0xE0, 0x23, 0x00, 0x00, 0xE0]);
+        chip.memory[0x300..0x302].copy_from_slice(&[0x00, 0xEE]);
+        let mut profile = Profile::new(chip.memory.len());
+        for _ in 0..2 {
+            profile.record(0x204, 0x00E0, &[], &chip.memory);
+            profile.record(0x200, 0x00E0, &[], &chip.memory);
+            profile.record(0x300, 0x00EE, &[0x202], &chip.memory);
+        }
+        profile.record(0x202, 0x2300, &[], &chip.memory);
+        profile.record(0x300, 0x00EE, &[0x202], &chip.memory);
+
+        let report = profile.report(&chip, 2, &Symbols::default());
+        let lines: Vec<&str> = report.lines().map(str::trim).collect();
+        // Most executed first, ties by address, only the top 2: 204 is left out
+        assert_eq!(
+            lines[..6],
+            [
+                "8 instructions executed",
+                "",
+                "Addresses",
+                "count       %  address",
+                "3  37.50%  300  00EE  RET",
+                "2  25.00%  200  00E0  CLS",
+            ]
+        );
+        assert_eq!(lines[6], "");
+        assert_eq!(lines[9], "4  50.00%  00E0");
+        assert_eq!(lines[10], "3  37.50%  00EE");
+        assert_eq!(lines[14], "5  62.50%           8 100.00%  200");
+        assert_eq!(lines[15], "3  37.50%           3  37.50%  300");
+        assert_eq!(lines.len(), 16);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::effects::Effects;
+use crate::palette::Palette;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+
+/// Emulation state shown as an icon in the corner of the display.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Status {
+    Running,
+    Paused,
+    FastForward,
+}
+
+impl Status {
+    /// Returns the icon as rows of 6 bit wide bitmaps, or None for no icon.
+    fn icon(self) -> Option<[u8; 5]> {
+        match self {
+            Status::Running => None,
+            Status::Paused => Some([0b110110; 5]),
+            Status::FastForward => Some([0b100100, 0b110110, 0b111111, 0b110110, 0b100100]),
+        }
+    }
+}
+
+/// Draws the CHIP-8 display through a streaming texture with one texel per
+/// CHIP-8 pixel, which the renderer scales up to the window in a single copy.
+/// The picture keeps its aspect ratio, with the rest of the window left in
+/// the background colour. With CRT effects enabled the frame is upscaled and
+/// processed on the CPU first.
+pub struct Renderer<'a> {
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
+    width: usize,
+    height: usize,
+    pub palette: Palette,
+    /// Only scale by whole multiples to keep all pixels the same size
+    pub integer_scaling: bool,
+    /// Fraction of its brightness a pixel keeps per frame after being turned
+    /// off, emulating phosphor afterglow. 0 disables the effect.
+    pub persistence: f32,
+    /// Per pixel: the last value it was set to and its current brightness
+    glow: Vec<(u8, f32)>,
+    fading: bool,
+    pub effects: Effects,
+    /// Draw a border around the window in the foreground colour, a visual
+    /// substitute for the beeper
+    pub beep: bool,
+    pub status: Status,
+    /// Upscaled frame the effects are applied to
+    frame: Vec<u8>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(
+        texture_creator: &'a TextureCreator<WindowContext>,
+        width: usize,
+        height: usize,
+        palette: Palette,
+    ) -> Result<Self, String> {
+        Ok(Renderer {
+            texture_creator,
+            texture: create_texture(texture_creator, width, height)?,
+            width,
+            height,
+            palette,
+            integer_scaling: false,
+            persistence: 0.0,
+            glow: vec![(0, 0.0); width * height],
+            fading: false,
+            effects: Effects::default(),
+            beep: false,
+            status: Status::Running,
+            frame: Vec::new(),
+        })
+    }
+
+    /// Uploads `gfx`, a `width` x `height` buffer with one palette index per
+    /// pixel, to the texture. The texture is recreated when the resolution changes.
+    ///
+    /// With persistence enabled this advances the afterglow by one frame, so
+    /// it should be called once per frame for as long as `is_fading` is true.
+    pub fn update(&mut self, gfx: &[u8], width: usize, height: usize) -> Result<(), String> {
+        if width != self.width || height != self.height {
+            self.width = width;
+            self.height = height;
+            self.glow = vec![(0, 0.0); width * height];
+        }
+        let scale = self.effects.scale();
+        let query = self.texture.query();
+        if (query.width, query.height) != ((width * scale) as u32, (height * scale) as u32) {
+            self.texture = create_texture(self.texture_creator, width * scale, height * scale)?;
+        }
+
+        self.fading = false;
+        for (&pixel, glow) in gfx.iter().zip(self.glow.iter_mut()) {
+            if pixel != 0 {
+                *glow = (pixel, 1.0);
+            } else if glow.1 > 0.0 {
+                glow.1 *= self.persistence;
+                // Below this the pixel can't be told apart from the background
+                if glow.1 < 1.0 / 255.0 {
+                    glow.1 = 0.0;
+                } else {
+                    self.fading = true;
+                }
+            }
+        }
+
+        let background = self.palette.background();
+        let colors: Vec<Color> = self
+            .glow
+            .iter()
+            .map(|&(pixel, brightness)| blend(background, self.palette.color(pixel), brightness))
+            .collect();
+
+        // Upscale into the frame buffer, so far as the effects need it
+        let (frame_width, frame_height) = (width * scale, height * scale);
+        self.frame.resize(frame_width * frame_height * 3, 0);
+        for (y, line) in self.frame.chunks_mut(frame_width * 3).enumerate() {
+            for (x, texel) in line.chunks_mut(3).enumerate() {
+                let color = colors[(y / scale) * width + x / scale];
+                texel.copy_from_slice(&[color.r, color.g, color.b]);
+            }
+        }
+        if self.effects.enabled() {
+            self.effects
+                .apply(&mut self.frame, frame_width, frame_height);
+        }
+
+        let frame = &self.frame;
+        self.texture.with_lock(None, |buffer, pitch| {
+            for (y, line) in frame.chunks(frame_width * 3).enumerate() {
+                buffer[y * pitch..y * pitch + line.len()].copy_from_slice(line);
+            }
+        })
+    }
+
+    /// Returns whether turned off pixels are still glowing.
+    pub fn is_fading(&self) -> bool {
+        self.fading
+    }
+
+    /// Copies the texture onto the canvas, with the beep border and status
+    /// icon on top.
+    pub fn draw(&self, canvas: &mut WindowCanvas) -> Result<(), String> {
+        let output = canvas.output_size()?;
+        let viewport = self.viewport(output);
+        canvas.set_draw_color(self.palette.background());
+        canvas.clear();
+        canvas.copy(&self.texture, None, viewport)?;
+        if self.beep {
+            let (width, height) = output;
+            let thickness = (width.min(height) / 40).max(2);
+            canvas.set_draw_color(self.palette.color(1));
+            canvas.fill_rects(&[
+                Rect::new(0, 0, width, thickness),
+                Rect::new(0, height.saturating_sub(thickness) as i32, width, thickness),
+                Rect::new(0, 0, thickness, height),
+                Rect::new(width.saturating_sub(thickness) as i32, 0, thickness, height),
+            ])?;
+        }
+        if let Some(icon) = self.status.icon() {
+            self.draw_icon(canvas, viewport, &icon)?;
+        }
+        Ok(())
+    }
+
+    /// Draws `icon` in the top left corner of `viewport` with one CHIP-8
+    /// pixel per bit, on a background coloured box.
+    fn draw_icon(
+        &self,
+        canvas: &mut WindowCanvas,
+        viewport: Rect,
+        icon: &[u8; 5],
+    ) -> Result<(), String> {
+        let size = (viewport.height() / self.height as u32).max(1);
+        let (x, y) = (viewport.x() + size as i32, viewport.y() + size as i32);
+        canvas.set_draw_color(self.palette.background());
+        canvas.fill_rect(Rect::new(x, y, size * 8, size * 7))?;
+
+        let rects: Vec<Rect> = icon
+            .iter()
+            .enumerate()
+            .flat_map(|(row, bits)| {
+                (0..6)
+                    .filter(move |column| bits & (0b100000 >> column) != 0)
+                    .map(move |column| {
+                        Rect::new(
+                            x + (column + 1) * size as i32,
+                            y + (row as i32 + 1) * size as i32,
+                            size,
+                            size,
+                        )
+                    })
+            })
+            .collect();
+        canvas.set_draw_color(self.palette.color(1));
+        canvas.fill_rects(&rects)
+    }
+
+    /// Returns the largest area of an `output` sized canvas the display fits
+    /// into, centered.
+    pub fn viewport(&self, output: (u32, u32)) -> Rect {
+        let (width, height) = (self.width as u32, self.height as u32);
+        let (scaled_width, scaled_height) = if self.integer_scaling {
+            let scale = (output.0 / width).min(output.1 / height).max(1);
+            (width * scale, height * scale)
+        } else if output.0 * height > output.1 * width {
+            (output.1 * width / height, output.1)
+        } else {
+            (output.0, output.0 * height / width)
+        };
+        Rect::new(
+            (output.0 as i32 - scaled_width as i32) / 2,
+            (output.1 as i32 - scaled_height as i32) / 2,
+            scaled_width,
+            scaled_height,
+        )
+    }
+}
+
+/// Mixes `to` into `from` by `amount` from 0.0 to 1.0.
+fn blend(from: Color, to: Color, amount: f32) -> Color {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount) as u8;
+    Color::RGB(mix(from.r, to.r), mix(from.g, to.g), mix(from.b, to.b))
+}
+
+fn create_texture<'a>(
+    texture_creator: &'a TextureCreator<WindowContext>,
+    width: usize,
+    height: usize,
+) -> Result<Texture<'a>, String> {
+    texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+        .map_err(|e| e.to_string())
+}
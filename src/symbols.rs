@@ -0,0 +1,197 @@
+use crate::expr::parse_number;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Names for addresses in a ROM, read from a symbol file with one label per
+/// line:
+///
+/// ```text
+/// main 0x200
+/// draw_ball = 0x2A4
+/// ```
+///
+/// Addresses are hex, with or without "0x". Everything after ";" is a
+/// comment. `asm` writes these files next to the ROM.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    by_name: HashMap<String, u16>,
+    by_address: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    /// Loads the symbols for `rom`: from `path` if given, otherwise from the
+    /// ROM's file name with the extension replaced by ".sym" if that exists.
+    pub fn for_rom(rom: &str, path: Option<&Path>) -> Result<Symbols, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = PathBuf::from(rom).with_extension("sym");
+                if !path.exists() {
+                    return Ok(Symbols::default());
+                }
+                path
+            }
+        };
+        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Symbols::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", index + 1, message);
+            let words: Vec<&str> = line.split_whitespace().filter(|&w| w != "=").collect();
+            let (name, address) = match words[..] {
+                [name, address] => (name, address),
+                _ => return Err(error(format!("expected <label> <address>: {}", line))),
+            };
+            let address = u16::from_str_radix(address.trim_start_matches("0x"), 16)
+                .map_err(|_| error(format!("invalid address: {}", address)))?;
+            symbols.insert(name, address).map_err(error)?;
+        }
+        Ok(symbols)
+    }
+
+    /// Adds a label, which must not exist yet.
+    pub fn insert(&mut self, name: &str, address: u16) -> Result<(), String> {
+        if self.by_name.insert(name.to_string(), address).is_some() {
+            return Err(format!("duplicate label: {}", name));
+        }
+        // With several labels at an address, the first one names it
+        self.by_address
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Returns the address of the label `name`.
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Returns the label at `address`.
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(|name| name.as_str())
+    }
+
+    /// Describes `address` as an offset from the closest label before it,
+    /// like "draw_ball+4", if there is one.
+    pub fn locate(&self, address: u16) -> Option<String> {
+        let (&label, name) = self.by_address.range(..=address).next_back()?;
+        Some(match address - label {
+            0 => name.clone(),
+            offset => format!("{}+{:X}", name, offset),
+        })
+    }
+
+    /// Formats `address` as hex followed by its location, if known.
+    pub fn format(&self, address: u16) -> String {
+        match self.locate(address) {
+            Some(location) => format!("{:03X} <{}>", address, location),
+            None => format!("{:03X}", address),
+        }
+    }
+
+    /// Parses an address given as a label or a number, which is decimal
+    /// unless it starts with "0x".
+    pub fn parse_address(&self, address: &str) -> Result<u16, String> {
+        match self.address(address) {
+            Some(address) => Ok(address),
+            None => parse_number(address)
+                .ok()
+                .and_then(|number| u16::try_from(number).ok())
+                .ok_or_else(|| format!("Invalid address or unknown label: {}", address)),
+        }
+    }
+
+    /// Writes the symbols in the format `parse` reads, ordered by address.
+    pub fn to_text(&self) -> String {
+        let mut labels: Vec<(&String, &u16)> = self.by_name.iter().collect();
+        labels.sort_by_key(|&(name, &address)| (address, name));
+        labels
+            .iter()
+            .map(|(name, address)| format!("{} 0x{:03X}\n", name, address))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_forms() {
+        let symbols =
+            Symbols::parse("; labels\nmain 0x200\ndraw_ball = 2A4 ; comment\n\nloop 0x200\n")
+                .unwrap();
+        assert_eq!(symbols.address("main"), Some(0x200));
+        assert_eq!(symbols.address("draw_ball"), Some(0x2A4));
+        assert_eq!(symbols.address("loop"), Some(0x200));
+        assert_eq!(symbols.address("missing"), None);
+        // The first label at an address names it
+        assert_eq!(symbols.name(0x200), Some("main"));
+        assert!(Symbols::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejected() {
+        assert_eq!(
+            Symbols::parse("main 0x200\nmain 0x300").err(),
+            Some("line 2: duplicate label: main".to_string())
+        );
+        assert_eq!(
+            Symbols::parse("main").err(),
+            Some("line 1: expected <label> <address>: main".to_string())
+        );
+        assert_eq!(
+            Symbols::parse("main 0x200 0x300").err(),
+            Some("line 1: expected <label> <address>: main 0x200 0x300".to_string())
+        );
+        assert_eq!(
+            Symbols::parse("main 0xG00").err(),
+            Some("line 1: invalid address: 0xG00".to_string())
+        );
+        assert!(Symbols::parse("main 10000").is_err());
+    }
+
+    #[test]
+    fn locate_and_format() {
+        let symbols = Symbols::parse("main 0x200\ndraw 0x2A4").unwrap();
+        assert_eq!(symbols.locate(0x1FF), None);
+        assert_eq!(symbols.locate(0x200), Some("main".to_string()));
+        assert_eq!(symbols.locate(0x2A3), Some("main+A3".to_string()));
+        assert_eq!(symbols.locate(0x2B0), Some("draw+C".to_string()));
+        assert_eq!(symbols.format(0x2A4), "2A4 <draw>");
+        assert_eq!(symbols.format(0x100), "100");
+    }
+
+    #[test]
+    fn addresses() {
+        let symbols = Symbols::parse("main 0x200").unwrap();
+        assert_eq!(symbols.parse_address("main"), Ok(0x200));
+        assert_eq!(symbols.parse_address("0x2A4"), Ok(0x2A4));
+        assert_eq!(symbols.parse_address("676"), Ok(0x2A4));
+        assert!(symbols.parse_address("2A4").is_err());
+        assert!(symbols.parse_address("65536").is_err());
+        assert!(symbols.parse_address("nowhere").is_err());
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let text = "main 0x200\nloop 0x200\ndraw 0x2A4\n";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.to_text(), "loop 0x200\nmain 0x200\ndraw 0x2A4\n");
+        let again = Symbols::parse(&symbols.to_text()).unwrap();
+        assert_eq!(again.to_text(), symbols.to_text());
+    }
+}
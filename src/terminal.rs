@@ -0,0 +1,270 @@
+use crate::chip8::{HEIGHT, WIDTH};
+use crate::config::Config;
+use crate::graphics::Protocol;
+use crate::keymap::KeyMap;
+use crate::palette::Palette;
+use crate::symbols::Symbols;
+use crate::trace::{self, Tracer};
+use crate::FRAMES_PER_SECOND;
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::pixels::Color;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a key counts as held after the terminal reports it. Terminals
+/// only send key presses, repeating them after a delay while the key is
+/// down, so the first press has to last until the repeats start.
+const FIRST_HOLD: Duration = Duration::from_millis(550);
+const REPEAT_HOLD: Duration = Duration::from_millis(100);
+
+/// Characters the display is drawn with.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Charset {
+    /// "▀" with separate colours for the upper and lower half, 2 pixels per
+    /// character
+    HalfBlock,
+    /// Braille patterns in the foreground colour, 8 pixels per character
+    Braille,
+}
+
+impl Charset {
+    pub fn from_name(name: &str) -> Result<Charset, String> {
+        match name {
+            "halfblock" => Ok(Charset::HalfBlock),
+            "braille" => Ok(Charset::Braille),
+            _ => Err(format!(
+                "Unknown charset: {} (expected halfblock or braille)",
+                name
+            )),
+        }
+    }
+}
+
+/// Puts the terminal into raw mode and switches to the alternate screen,
+/// undoing both when dropped.
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    fn new() -> Result<Self, String> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        Ok(RawTerminal {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("stty: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "stty: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the CHIP-8 key for a character typed in the terminal. As only
+/// characters are known, scancode bindings match the key that types the
+/// character on a US layout.
+fn lookup(keymap: &KeyMap, byte: u8) -> Option<u8> {
+    let c = (byte as char).to_ascii_lowercase();
+    if !c.is_ascii_graphic() {
+        return None;
+    }
+    keymap.lookup(
+        Keycode::from_i32(c as i32),
+        Scancode::from_name(&c.to_ascii_uppercase().to_string()),
+    )
+}
+
+/// Runs `rom` in the terminal until Escape or Ctrl-C is pressed.
+pub fn run(rom: &str, config: &Config, symbols: &Symbols) -> Result<(), String> {
+    let keymap = KeyMap::from_config(&config.keys)?;
+    let palette = Palette::from_config(&config.display)?;
+    let charset = Charset::from_name(&config.terminal.charset)?;
+    let graphics = Protocol::from_name(&config.terminal.graphics)?;
+    let scale = config.terminal.scale.max(1);
+    let bell = config.audio.enabled && !config.audio.mute;
+
+    let mut chip = crate::load(rom, config)?;
+    let mut tracer = Tracer::from_config(&config.trace, symbols)?;
+
+    // Reading stdin blocks, so it happens on its own thread
+    let (sender, input) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 64];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        while let Ok(n) = stdin.read(&mut buffer) {
+            if n == 0 || sender.send(buffer[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let _terminal = RawTerminal::new()?;
+    let mut stdout = io::stdout();
+    // When each held key is released
+    let mut held: [Option<Instant>; 16] = [None; 16];
+    let mut draw = true;
+
+    let frame_duration = Duration::from_secs(1) / FRAMES_PER_SECOND;
+    let mut next_frame = Instant::now();
+    let mut result = Ok(());
+    'running: loop {
+        let now = Instant::now();
+        loop {
+            let bytes = match input.try_recv() {
+                Ok(bytes) => bytes,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'running,
+            };
+            // A lone escape is the Escape key, otherwise it starts the
+            // sequence for a special key, which aren't mapped. Keys typed
+            // before it still count.
+            if bytes == [0x1b] || bytes.contains(&0x03) {
+                break 'running;
+            }
+            let typed = match bytes.iter().position(|&byte| byte == 0x1b) {
+                Some(escape) => &bytes[..escape],
+                None => &bytes[..],
+            };
+            for &byte in typed {
+                if let Some(key) = lookup(&keymap, byte) {
+                    let key = key as usize;
+                    let hold = if held[key].is_some() {
+                        REPEAT_HOLD
+                    } else {
+                        FIRST_HOLD
+                    };
+                    held[key] = Some(now + hold);
+                    chip.key[key] = 1;
+                }
+            }
+        }
+        for (key, release) in held.iter_mut().enumerate() {
+            if release.is_some_and(|release| release <= now) {
+                *release = None;
+                chip.key[key] = 0;
+            }
+        }
+
+        let cycles = crate::frame_cycles(config.emulation.speed, chip.frame);
+        let frame = match trace::run_frame(&mut chip, cycles, &mut tracer) {
+            Ok(frame) => frame,
+            Err(fault) => {
+                result = Err(fault.to_string());
+                break;
+            }
+        };
+        draw |= frame.draw;
+        let mut output = String::new();
+        if bell && frame.sound.is_some_and(|sound| sound > 0) {
+            output.push('\x07');
+        }
+        if draw {
+            match graphics {
+                Some(protocol) => {
+                    output.push_str("\x1b[H");
+                    protocol.encode(&mut output, &chip.gfx, &palette, scale);
+                }
+                None => render(&mut output, &chip.gfx, &palette, charset),
+            }
+            draw = false;
+        }
+        if !output.is_empty() {
+            stdout
+                .write_all(output.as_bytes())
+                .and_then(|_| stdout.flush())
+                .map_err(|e| e.to_string())?;
+        }
+
+        // Sleep until the next frame is due, unless we are already late
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    trace::finish(&chip, &mut tracer, config, symbols)?;
+    result
+}
+
+/// Appends the escape sequences drawing `gfx` in the top left corner of the
+/// terminal to `output`.
+fn render(output: &mut String, gfx: &[u8], palette: &Palette, charset: Charset) {
+    let pixel = |x: usize, y: usize| gfx[y * WIDTH + x];
+    output.push_str("\x1b[H");
+    match charset {
+        Charset::HalfBlock => {
+            for y in (0..HEIGHT).step_by(2) {
+                // Colours are only sent when they change from the last character
+                let mut last = None;
+                for x in 0..WIDTH {
+                    let colors = (palette.color(pixel(x, y)), palette.color(pixel(x, y + 1)));
+                    if last != Some(colors) {
+                        push_color(output, 38, colors.0);
+                        push_color(output, 48, colors.1);
+                        last = Some(colors);
+                    }
+                    output.push('▀');
+                }
+                output.push_str("\x1b[0m\r\n");
+            }
+        }
+        Charset::Braille => {
+            // Dot numbering of the braille patterns, by row and column
+            const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+            for y in (0..HEIGHT).step_by(4) {
+                push_color(output, 38, palette.color(1));
+                push_color(output, 48, palette.background());
+                for x in (0..WIDTH).step_by(2) {
+                    let mut pattern = 0x2800;
+                    for (row, dots) in DOTS.iter().enumerate() {
+                        for (column, dot) in dots.iter().enumerate() {
+                            if pixel(x + column, y + row) != 0 {
+                                pattern |= dot;
+                            }
+                        }
+                    }
+                    output.push(std::char::from_u32(pattern).unwrap());
+                }
+                output.push_str("\x1b[0m\r\n");
+            }
+        }
+    }
+}
+
+/// Appends a 24-bit colour escape sequence, 38 for the foreground or 48 for
+/// the background.
+fn push_color(output: &mut String, target: u8, color: Color) {
+    let _ = write!(
+        output,
+        "\x1b[{};2;{};{};{}m",
+        target, color.r, color.g, color.b
+    );
+}
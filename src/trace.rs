@@ -0,0 +1,194 @@
+use crate::chip8::{Chip8, Fault, Frame};
+use crate::config::{Config, TraceConfig};
+use crate::disasm::disassemble;
+use crate::symbols::Symbols;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Logs executed instructions to a file, one line each:
+///
+/// ```text
+/// cycle=1 pc=0200 op=6A02 v=00000000000000000000020000000000 i=0000 sp=0 dt=00 st=00 ; LD VA, 0x02
+/// ```
+///
+/// `pc` and `op` are the address and opcode of the instruction, the other
+/// fields the state after it was executed, with `v` holding V0 to VF. When
+/// there are return addresses on the stack they follow as `stack=0202,0310`,
+/// and memory the instruction wrote as `w=0300:01,0301:02`. All values but
+/// the cycle count are hex. Everything after ";" is a comment: the
+/// disassembly, preceded by the location when there are symbols.
+pub struct Tracer {
+    out: BufWriter<File>,
+    path: String,
+    /// Only instructions in this address range are logged
+    range: (u16, u16),
+    /// In ring buffer mode the most recent lines, written out on a fault
+    ring: Option<VecDeque<String>>,
+    capacity: usize,
+    cycle: u64,
+    symbols: Symbols,
+    /// The first error writing the file
+    error: Option<String>,
+}
+
+impl Tracer {
+    /// Creates the tracer `config` asks for, if any.
+    pub fn from_config(config: &TraceConfig, symbols: &Symbols) -> Result<Option<Tracer>, String> {
+        let path = match &config.file {
+            Some(path) => path.display().to_string(),
+            None => return Ok(None),
+        };
+        let range = match &config.range {
+            Some(range) => parse_range(range)?,
+            None => (0, 0xFFFF),
+        };
+        let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Some(Tracer {
+            out: BufWriter::new(file),
+            path,
+            range,
+            ring: if config.ring > 0 {
+                Some(VecDeque::with_capacity(config.ring))
+            } else {
+                None
+            },
+            capacity: config.ring,
+            cycle: 0,
+            symbols: symbols.clone(),
+            error: None,
+        }))
+    }
+
+    /// Logs the instruction `chip` just executed from `pc`.
+    pub fn record(&mut self, chip: &Chip8, pc: u16) {
+        self.cycle += 1;
+        if pc < self.range.0 || pc > self.range.1 {
+            return;
+        }
+        let line = format_line(self.cycle, chip, pc, &self.symbols);
+        match &mut self.ring {
+            Some(ring) => {
+                if ring.len() == self.capacity {
+                    ring.pop_front();
+                }
+                ring.push_back(line);
+            }
+            None => self.write(&line),
+        }
+    }
+
+    /// Logs `fault`, after the instructions leading up to it in ring buffer mode.
+    pub fn fault(&mut self, fault: &Fault) {
+        if let Some(ring) = &mut self.ring {
+            let lines: Vec<String> = ring.drain(..).collect();
+            for line in &lines {
+                self.write(line);
+            }
+        }
+        self.write(&format!("; fault: {}", fault));
+    }
+
+    /// Flushes the file and returns the first error writing it, if any.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if let Err(e) = self.out.flush() {
+            self.error.get_or_insert(format!("{}: {}", self.path, e));
+        }
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(format!("{}: {}", self.path, e));
+            }
+        }
+    }
+}
+
+/// Runs a frame of `chip`, logging it to `tracer` if there is one.
+pub fn run_frame(
+    chip: &mut Chip8,
+    cycles: u32,
+    tracer: &mut Option<Tracer>,
+) -> Result<Frame, Fault> {
+    match tracer {
+        Some(tracer) => {
+            let result = chip.run_frame_with(cycles, |chip, pc| tracer.record(chip, pc));
+            if let Err(fault) = &result {
+                tracer.fault(fault);
+            }
+            result
+        }
+        None => chip.run_frame(cycles),
+    }
+}
+
+/// Writes the profile and flushes the trace at the end of a run. Both files
+/// are written even if one of them fails.
+pub fn finish(
+    chip: &Chip8,
+    tracer: &mut Option<Tracer>,
+    config: &Config,
+    symbols: &Symbols,
+) -> Result<(), String> {
+    let profiled = match &chip.profile {
+        Some(profile) => profile.write(chip, &config.profile, symbols),
+        None => Ok(()),
+    };
+    if let Some(tracer) = tracer {
+        tracer.finish()?;
+    }
+    profiled
+}
+
+fn format_line(cycle: u64, chip: &Chip8, pc: u16, symbols: &Symbols) -> String {
+    let mut v = String::with_capacity(32);
+    for register in chip.v.iter() {
+        let _ = write!(v, "{:02X}", register);
+    }
+    let mut line = format!(
+        "cycle={} pc={:04X} op={:04X} v={} i={:04X} sp={:X} dt={:02X} st={:02X}",
+        cycle, pc, chip.opcode, v, chip.i, chip.sp, chip.delay_timer, chip.sound_timer
+    );
+    if !chip.stack().is_empty() {
+        let stack: Vec<String> = chip.stack().iter().map(|a| format!("{:04X}", a)).collect();
+        let _ = write!(line, " stack={}", stack.join(","));
+    }
+    if !chip.last_writes.is_empty() {
+        let writes: Vec<String> = chip
+            .last_writes
+            .iter()
+            .map(|write| format!("{:04X}:{:02X}", write.address, write.value))
+            .collect();
+        let _ = write!(line, " w={}", writes.join(","));
+    }
+    line.push_str(" ; ");
+    if let Some(location) = symbols.locate(pc) {
+        let _ = write!(line, "{}: ", location);
+    }
+    line.push_str(&disassemble(chip.opcode, symbols));
+    line
+}
+
+/// Parses an address range like "200-2FF", in hex.
+fn parse_range(range: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid address range: {} (expected e.g. 200-2FF)", range);
+    let parse = |address: &str| {
+        u16::from_str_radix(address.trim().trim_start_matches("0x"), 16).map_err(|_| invalid())
+    };
+    match range.split_once('-') {
+        Some((from, to)) => {
+            let (from, to) = (parse(from)?, parse(to)?);
+            if from > to {
+                return Err(invalid());
+            }
+            Ok((from, to))
+        }
+        None => Err(invalid()),
+    }
+}